@@ -0,0 +1,68 @@
+//! Compares `--parallel-chunk` sizes, when decrypting many tiny files across `--jobs` threads, as `process_batch` of the CLI does it.
+//!
+//! Files are decrypted in memory, so only scheduling overhead is compared, not the disk. Run with `cargo bench --bench chunk`.
+
+use asset_decrypter::{Decrypter, FileType};
+use criterion::{
+    BenchmarkId, Criterion, Throughput, criterion_group, criterion_main,
+};
+use rayon::{ThreadPoolBuilder, prelude::*};
+use std::{hint::black_box, sync::mpsc::channel};
+
+const KEY: &str = "d41d8cd98f00b204e9800998ecf8427e";
+const FILE_COUNT: usize = 20_000;
+/// Size of a tiny image, such as icon or tile.
+const FILE_SIZE: usize = 1024;
+const JOBS: usize = 4;
+const CHUNK_SIZES: &[usize] = &[1, 4, 16, 64, 256];
+
+fn bench_chunk(c: &mut Criterion) {
+    let mut decrypter = Decrypter::new();
+    decrypter.set_key_from_str(KEY).unwrap();
+
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| i as u8).collect();
+    let file = decrypter.encrypt(&data).unwrap();
+    let files = vec![file; FILE_COUNT];
+
+    let pool = ThreadPoolBuilder::new().num_threads(JOBS).build().unwrap();
+
+    let mut group = c.benchmark_group("chunk");
+    group.throughput(Throughput::Elements(FILE_COUNT as u64));
+
+    for &chunk in CHUNK_SIZES {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(chunk),
+            &chunk,
+            |b, &chunk| {
+                b.iter(|| {
+                    let (sender, receiver) = channel();
+
+                    pool.install(|| {
+                        files.par_iter().with_min_len(chunk).for_each_init(
+                            || {
+                                let mut decrypter = Decrypter::new();
+                                decrypter.set_key_from_str(KEY).unwrap();
+                                (decrypter, sender.clone())
+                            },
+                            |(decrypter, sender), file| {
+                                let mut file = file.clone();
+                                decrypter
+                                    .decrypt_in_place(&mut file, FileType::PNG)
+                                    .unwrap();
+                                sender.send(black_box(file.len())).unwrap();
+                            },
+                        );
+                    });
+
+                    drop(sender);
+                    receiver.into_iter().count()
+                });
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_chunk);
+criterion_main!(benches);
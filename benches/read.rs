@@ -0,0 +1,80 @@
+//! Compares `read`-based and `mmap`-based reading of encrypted files, as `decrypt` command does them.
+//!
+//! Run with `cargo bench`.
+
+use asset_decrypter::{HEADER_LENGTH, RPGM_HEADER};
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use memmap2::Mmap;
+use std::{
+    fs::{File, create_dir_all, read, remove_dir_all, write},
+    hint::black_box,
+    io::Write,
+    path::PathBuf,
+};
+
+const FILE_COUNT: usize = 64;
+const FILE_SIZE: usize = 1024 * 1024;
+/// Fake header with encrypted source header, which is all that decryption touches.
+const ENCRYPTED_PREFIX_LENGTH: usize = RPGM_HEADER.len() + HEADER_LENGTH;
+/// Length of the prefix, which `--mmap` copies into memory for key detection, like `STREAM_PREFIX_LENGTH` of the CLI.
+const PREFIX_LENGTH: usize = 64 * 1024;
+
+/// Creates synthetic encrypted files in a temporary directory.
+fn create_files() -> (PathBuf, Vec<PathBuf>) {
+    let dir = std::env::temp_dir().join("rpgmasd-bench");
+    create_dir_all(&dir).unwrap();
+
+    let data: Vec<u8> = (0..FILE_SIZE).map(|i| i as u8).collect();
+    let files = (0..FILE_COUNT)
+        .map(|i| {
+            let path = dir.join(format!("{i}.rpgmvp"));
+            write(&path, &data).unwrap();
+            path
+        })
+        .collect();
+
+    (dir, files)
+}
+
+fn bench_read(c: &mut Criterion) {
+    let (dir, files) = create_files();
+    // Output is actually written, otherwise mapped pages would never be touched
+    let output_path = dir.join("output.png");
+
+    let mut group = c.benchmark_group("read");
+    group.throughput(Throughput::Bytes((FILE_COUNT * FILE_SIZE) as u64));
+
+    group.bench_function("read", |b| {
+        b.iter(|| {
+            for path in &files {
+                let mut data = read(path).unwrap();
+                data[ENCRYPTED_PREFIX_LENGTH - 1] ^= 0xFF;
+                write(&output_path, black_box(&data[HEADER_LENGTH..])).unwrap();
+            }
+        });
+    });
+
+    group.bench_function("mmap", |b| {
+        b.iter(|| {
+            for path in &files {
+                let file = File::open(path).unwrap();
+                let map = unsafe { Mmap::map(&file) }.unwrap();
+                let prefix_length = map.len().min(PREFIX_LENGTH);
+                let mut prefix = map[..prefix_length].to_vec();
+                prefix[ENCRYPTED_PREFIX_LENGTH - 1] ^= 0xFF;
+
+                let mut output = File::create(&output_path).unwrap();
+                output
+                    .write_all(black_box(&prefix[HEADER_LENGTH..]))
+                    .unwrap();
+                output.write_all(black_box(&map[prefix_length..])).unwrap();
+            }
+        });
+    });
+
+    group.finish();
+    remove_dir_all(dir).unwrap();
+}
+
+criterion_group!(benches, bench_read);
+criterion_main!(benches);
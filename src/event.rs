@@ -0,0 +1,36 @@
+use crate::report::{ProcessOutcome, RunReport};
+use std::path::Path;
+
+/// Event, emitted by [`Processor`](crate::Processor) during processing.
+///
+/// Frontends can subscribe to them to display progress or log the results. When files are processed by multiple `--jobs` threads, their events are emitted as each of them starts and finishes, so they may come in a different order than the files were found.
+// CLI itself only handles some of the payloads
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub enum Event<'e> {
+    /// Processing of the file has started.
+    FileStarted(&'e Path),
+    /// Processing of the file has finished.
+    FileFinished(&'e ProcessOutcome),
+    /// Processing of the file has failed, and the batch continues without `--fail-fast`.
+    FileFailed {
+        source: &'e Path,
+        error: &'e anyhow::Error,
+    },
+    /// Key, that wasn't seen before, was extracted from `source` file.
+    KeyDetected { key: &'e str, source: &'e Path },
+    /// All files of `encrypt` or `decrypt` run were processed.
+    BatchDone(&'e RunReport),
+}
+
+/// Callback, that receives emitted [`Event`]s.
+///
+/// It's only called from the thread that runs processing, but must be [`Sync`] since processor is shared with worker threads.
+pub type EventHandler<'a> = Box<dyn FnMut(Event<'_>) + Sync + 'a>;
+
+/// Passes the event to the handler, if it's set.
+pub fn emit(handler: &mut Option<EventHandler<'_>>, event: Event<'_>) {
+    if let Some(handler) = handler {
+        handler(event);
+    }
+}
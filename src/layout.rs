@@ -0,0 +1,98 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Default mapping of RPG Maker's asset naming conventions to their canonical folders.
+///
+/// Patterns are matched against output file name, `*` matches any sequence of characters, `?` matches any single character.
+pub const DEFAULT_LAYOUT: &[(&str, &str)] = &[
+    ("!*.png", "img/characters"),
+    ("$*.png", "img/characters"),
+    ("Actor?_?.png", "img/sv_actors"),
+    ("*_A?.png", "img/tilesets"),
+    ("*_B.png", "img/tilesets"),
+    ("*_C.png", "img/tilesets"),
+    ("*_D.png", "img/tilesets"),
+    ("*_E.png", "img/tilesets"),
+    ("Balloon.png", "img/system"),
+    ("ButtonSet.png", "img/system"),
+    ("Damage.png", "img/system"),
+    ("GameOver.png", "img/system"),
+    ("IconSet.png", "img/system"),
+    ("Loading.png", "img/system"),
+    ("Shadow?.png", "img/system"),
+    ("States.png", "img/system"),
+    ("Weapons?.png", "img/system"),
+    ("Window.png", "img/system"),
+];
+
+/// Rule, routing files with names matching `pattern` into `folder`.
+#[derive(Clone)]
+pub struct LayoutRule {
+    pattern: String,
+    folder: PathBuf,
+}
+
+impl LayoutRule {
+    pub fn new(pattern: &str, folder: &str) -> Self {
+        Self {
+            pattern: pattern.to_owned(),
+            folder: PathBuf::from(folder),
+        }
+    }
+}
+
+impl FromStr for LayoutRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (pattern, folder) = value
+            .split_once('=')
+            .ok_or("expected `pattern=folder` format")?;
+
+        if pattern.is_empty() || folder.is_empty() {
+            return Err("pattern and folder must not be empty".into());
+        }
+
+        Ok(Self::new(pattern, folder))
+    }
+}
+
+/// Returns the folder of the first rule, which pattern matches the passed file name.
+pub fn canonical_folder<'a>(
+    rules: &'a [LayoutRule],
+    file_name: &str,
+) -> Option<&'a Path> {
+    rules
+        .iter()
+        .find(|rule| wildcard_match(&rule.pattern, file_name))
+        .map(|rule| rule.folder.as_path())
+}
+
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in pattern, and position in text it was matched at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -3,19 +3,57 @@
 #![allow(clippy::cast_possible_truncation)]
 #![allow(clippy::cast_possible_wrap)]
 #![allow(clippy::cast_sign_loss)]
+#![allow(clippy::cast_precision_loss)]
 #![allow(clippy::deref_addrof)]
+#![allow(clippy::struct_excessive_bools)]
 
-use anyhow::{Result, bail};
-use asset_decrypter::{Decrypter, FileType, HEADER_LENGTH, RPGM_HEADER};
-use clap::{Parser, Subcommand, ValueEnum, value_parser};
-use serde_json::{Value, from_str};
+mod event;
+mod layout;
+mod report;
+mod signature;
+
+use anyhow::{Context, Result, bail};
+use asset_decrypter::{
+    Decrypter, FileType, HEADER_LENGTH, KEY_STR_LENGTH, RPGM_HEADER,
+};
+use chrono::DateTime;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum, value_parser};
+use event::{Event, EventHandler, emit};
+use layout::{DEFAULT_LAYOUT, LayoutRule, canonical_folder};
+use log::{Level, LevelFilter, debug, error, info, warn};
+use md5::{Digest, Md5};
+use memmap2::Mmap;
+use rayon::{ThreadPoolBuilder, prelude::*};
+use report::{ProcessOutcome, RunReport, Status};
+use serde_json::{Value, from_str, json, to_string, to_string_pretty};
+use sha2::Sha256;
+use signature::{
+    CustomValidator, Signature, SignatureValidator, detect_file_type,
+    fallback_extensions, verify_decrypted,
+};
 use std::{
-    ffi::OsStr,
-    fs::{read, read_dir, read_to_string, write},
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs::{
+        File, FileTimes, canonicalize, create_dir_all, metadata, read,
+        read_dir, read_to_string, remove_file, rename, write,
+    },
+    io::{self, BufRead, BufReader, Read, Write, copy, stdin},
+    mem::take,
+    ops::ControlFlow,
     path::{Path, PathBuf},
-    time::Instant,
+    process,
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+        mpsc::channel,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use strum_macros::EnumIs;
+use zip::ZipArchive;
 
 #[derive(Debug, Copy, Clone, ValueEnum)]
 pub enum Engine {
@@ -23,6 +61,59 @@ pub enum Engine {
     MZ,
 }
 
+impl Engine {
+    pub(crate) const fn as_str(self) -> &'static str {
+        match self {
+            Self::MV => "mv",
+            Self::MZ => "mz",
+        }
+    }
+
+    /// Returns the engine, which produces files with the passed encrypted extension.
+    fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            MV_PNG_EXT | MV_OGG_EXT | MV_M4A_EXT => Some(Self::MV),
+            MZ_PNG_EXT | MZ_OGG_EXT | MZ_M4A_EXT => Some(Self::MZ),
+            _ => None,
+        }
+    }
+}
+
+/// Format of the command output.
+#[derive(Debug, Copy, Clone, ValueEnum, EnumIs)]
+enum OutputFormat {
+    /// Human-readable text
+    Text,
+    /// Machine-readable JSON
+    Json,
+}
+
+/// Key derivation function, used to produce the key from `--passphrase`.
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum Kdf {
+    /// MD5 hash of the passphrase. That's how RPG Maker itself derives the key from "Encryption key" field in the editor
+    Md5,
+    /// First 16 bytes of SHA-256 hash of the passphrase
+    Sha256Trunc,
+}
+
+impl Kdf {
+    /// Derives the key from passphrase and returns it as a hex string.
+    fn derive_key(self, passphrase: &str) -> String {
+        let key: [u8; 16] = match self {
+            Self::Md5 => Md5::digest(passphrase).into(),
+            Self::Sha256Trunc => {
+                let hash = Sha256::digest(passphrase);
+                let mut key = [0; 16];
+                key.copy_from_slice(&hash[..16]);
+                key
+            }
+        };
+
+        to_hex(&key)
+    }
+}
+
 #[derive(Parser)]
 #[command(
     about = "Decrypt/encrypt RPG Maker MV/MZ audio and image assets.",
@@ -36,8 +127,14 @@ struct Cli {
     /// Encryption key for encryption/decryption. Decrypt command automatically finds the key from processed files, so you probably don't need to set it when decrypting.
     #[arg(short = 'e', long, global = true)]
     key: Option<String>,
+    /// Passphrase to derive the key from using `--kdf` function. The function must match the game's scheme
+    #[arg(long, global = true, conflicts_with = "key")]
+    passphrase: Option<String>,
+    /// Key derivation function for `--passphrase`
+    #[arg(long, default_value = "md5", global = true)]
+    kdf: Kdf,
     /// Game engine - `mv` or `mz`. Required for encryption
-    #[arg(short = 'E', long, global = true, value_parser = ["mv", "mz"])]
+    #[arg(short = 'E', long, global = true)]
     engine: Option<Engine>,
     /// Input directory
     #[arg(short, long, default_value = "./", value_parser = value_parser!(PathBuf), hide_default_value = true, global = true)]
@@ -48,6 +145,119 @@ struct Cli {
     /// File path (for single file processing or key extraction)
     #[arg(short, long, value_parser = value_parser!(PathBuf), global = true, conflicts_with = "input_dir")]
     file: Option<PathBuf>,
+    /// Additional signature, accepted when validating decrypted files, in `magic@offset=ext` format, where `magic` is a hex string. Decrypted files matching it get `ext` extension. Requires `--key` or the game's `System.json` in the input directory or its parents, since the detected key always decrypts the header to the built-in signature. Can be specified multiple times
+    #[arg(long = "signature", value_name = "MAGIC@OFFSET=EXT", global = true)]
+    signatures: Vec<Signature>,
+    /// Instead of failing on invalid signature, write decrypted file with `.invalid` suffix and continue processing
+    #[arg(long, global = true)]
+    keep_invalid: bool,
+    /// Read the key and engine from `.rpgm-key` state file in the input directory, if they're not specified, and write them there after processing. The file is stored in plaintext
+    #[arg(long, global = true)]
+    state_file: bool,
+    /// Place outputs into canonical RPG Maker folders (`img/characters`, `img/tilesets`, etc.) based on their file names
+    #[arg(long, global = true)]
+    canonicalize_layout: bool,
+    /// Additional `--canonicalize-layout` rule in `pattern=folder` format, where `pattern` may contain `*` and `?` wildcards. Takes precedence over built-in rules. Can be specified multiple times
+    #[arg(
+        long = "layout-rule",
+        value_name = "PATTERN=FOLDER",
+        global = true,
+        requires = "canonicalize_layout"
+    )]
+    layout_rules: Vec<LayoutRule>,
+    /// Output aggregated run summary as JSON to the specified file, or to stdout if no file or `-` is specified
+    #[arg(long, value_name = "PATH", num_args = 0..=1, default_missing_value = "-", global = true)]
+    summary_json: Option<PathBuf>,
+    /// Write a CSV report of all processed files to the specified file. Columns are `source,output,type,key,status,bytes,ms,engine`
+    #[arg(long, value_name = "PATH", value_parser = value_parser!(PathBuf), global = true)]
+    report_csv: Option<PathBuf>,
+    /// Same as `--from-list -`. Kept for compatibility
+    #[arg(long, global = true, hide = true, conflicts_with_all = ["file", "from_list"])]
+    input_list_stdin: bool,
+    /// Print only the bare key in `extract-key` command, without any labels or elapsed time
+    #[arg(long, global = true, conflicts_with = "format")]
+    print_key_only: bool,
+    /// Output format of `extract-key` and `types` commands. JSON output of `extract-key` has `key`, `source` (`system_json` or `encrypted_file`) and `file` fields
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    format: OutputFormat,
+    /// Compare decrypted `--file` against the reference file and report the first differing byte offset, instead of writing the output
+    #[arg(long, value_name = "PATH", value_parser = value_parser!(PathBuf), global = true, requires = "file")]
+    compare_with: Option<PathBuf>,
+    /// Process only files modified after the specified time, either RFC 3339 timestamp (`2024-01-31T12:00:00Z`) or UNIX epoch seconds
+    #[arg(long, value_name = "TIMESTAMP", value_parser = parse_timestamp, global = true)]
+    since: Option<SystemTime>,
+    /// Treat recoverable problems, such as empty input files, as errors
+    #[arg(long, global = true)]
+    strict: bool,
+    /// In `encrypt` command, leave existing output files untouched if they already match what would be written
+    #[arg(long, global = true)]
+    no_op_on_match: bool,
+    /// Skip files, which output already exists in the output directory. Allows resuming an interrupted run
+    #[arg(long, global = true)]
+    skip_existing: bool,
+    /// Descend into subdirectories of the input directory, mirroring their structure in the output directory
+    #[arg(short, long, global = true)]
+    recursive: bool,
+    /// Number of threads, processing files of the input directory in parallel. `0` uses all logical CPUs
+    #[arg(short, long, value_name = "N", default_value_t = 0, global = true)]
+    jobs: usize,
+    /// Minimum number of files, that each `--jobs` thread takes at once. Files are already split between threads adaptively, so larger chunks only reduce scheduling overhead with thousands of tiny files
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..), global = true)]
+    parallel_chunk: usize,
+    /// In `decrypt` command, detect file types from their content instead of extensions. Allows decrypting renamed files, or files without extension
+    #[arg(long, global = true)]
+    detect: bool,
+    /// In `decrypt` command, stream files larger than the specified size in bytes, instead of reading them into memory entirely
+    #[arg(long, value_name = "BYTES", default_value_t = 64 * 1024 * 1024, global = true)]
+    stream_threshold: u64,
+    /// In `encrypt` command, write `--key` to `encryptionKey` of the specified `System.json`, and set `hasEncryptedImages`/`hasEncryptedAudio` if images/audio were encrypted
+    #[arg(long, value_name = "PATH", value_parser = value_parser!(PathBuf), global = true)]
+    update_system_json: Option<PathBuf>,
+    /// Process only the specified asset types, comma-separated: `png`, `ogg`, `m4a`
+    #[arg(long, value_name = "TYPES", value_delimiter = ',', value_parser = parse_file_type, global = true)]
+    only: Vec<FileType>,
+    /// Print `source -> target` mapping of files, that would be processed, without reading or writing them. In `verify` command, decrypt only the leading bytes of files instead, predicting whether they'll pass the check
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Delete source files after their outputs were successfully written, and, in `decrypt` command, passed signature validation
+    #[arg(long, global = true)]
+    remove_source: bool,
+    /// Print more details, such as every processed file. Specify twice to print everything
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Print only errors, and output of `extract-key` and `types` commands
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Read newline-separated file paths to process from the specified file, or from stdin if it's `-`. Relative paths are resolved against the input directory
+    #[arg(long, value_name = "PATH", value_parser = value_parser!(PathBuf), global = true, conflicts_with = "file")]
+    from_list: Option<PathBuf>,
+    /// Set modification and access times of written files to the ones of their sources, so incremental builds don't consider them changed
+    #[arg(long, global = true)]
+    preserve_times: bool,
+    /// Abort on the first file, that fails to process. By default, failures are reported after processing all files. With `--jobs` other than `1`, files that are already being processed by other threads are still finished. Always set when processing a single `--file`, unless it's a zip archive
+    #[arg(long, global = true)]
+    fail_fast: bool,
+    /// In `decrypt` command, memory-map source files instead of reading them, so only their first 64 KiB, which key detection needs, are copied into memory, and the rest is written straight from the mapping. Takes precedence over `--stream-threshold`
+    #[arg(long, global = true)]
+    mmap: bool,
+    /// Follow symlinks when walking the input directory, processing every file and directory only once. By default, symlinked files are processed, but symlinked directories are skipped
+    #[arg(long, global = true)]
+    follow_symlinks: bool,
+    /// Instead of overwriting existing files, write outputs with `--suffix` inserted before their extension, e.g. `file.decrypted.png`. If that file exists too, a number is appended: `file.decrypted.2.png`
+    #[arg(long, global = true, conflicts_with_all = ["skip_existing", "no_op_on_match"])]
+    rename_on_conflict: bool,
+    /// Suffix for `--rename-on-conflict`. Defaults to `decrypted` or `encrypted`, depending on the command
+    #[arg(
+        long,
+        value_name = "SUFFIX",
+        value_parser = parse_suffix,
+        global = true,
+        requires = "rename_on_conflict"
+    )]
+    suffix: Option<String>,
+    /// Zero-pad too short `--key` or truncate too long one to 32 characters instead of failing. Decryption with such key will likely produce garbage
+    #[arg(long, global = true)]
+    lenient_key: bool,
 }
 
 #[derive(Subcommand, EnumIs, Clone, Copy)]
@@ -63,6 +273,8 @@ enum Command {
 
     /// Decrypts encrypted assets. Automatically deduces the key for each processed file
     ///
+    /// M4A files, that are actually MP3 or AAC, and PNG files, that are actually WebP, are only decrypted with `--key`, or with the key from the game's `System.json` in the input directory or its parents
+    ///
     /// .rpgmvo/.ogg_ => .ogg
     ///
     /// .rpgmvp/.png_ => .png
@@ -70,8 +282,25 @@ enum Command {
     /// .rpgmvm/.m4a_ => .m4a
     Decrypt,
 
+    /// Checks that encrypted assets decrypt to valid signatures, without writing anything. Exits with non-zero code if any file fails the check
+    Verify,
+
     /// Extracts key from file, specified in --file argument. Key can only be extracted from System.json file or RPG Maker encrypted file.
     ExtractKey,
+
+    /// Lists supported file types, along with their decrypted and encrypted extensions for each engine.
+    Types {
+        /// Same as `--format json`. Kept for compatibility
+        #[arg(long, hide = true)]
+        json: bool,
+    },
+}
+
+impl Command {
+    /// Returns whether the command decrypts the files, either to write or to verify them.
+    const fn is_decrypting(self) -> bool {
+        matches!(self, Self::Decrypt | Self::Verify)
+    }
 }
 
 const MV_PNG_EXT: &str = "rpgmvp";
@@ -91,212 +320,2523 @@ const DECRYPT_EXTENSIONS: &[&str] = &[
 ];
 const ENCRYPT_EXTENSIONS: &[&str] = &[PNG_EXT, OGG_EXT, M4A_EXT];
 
-struct Processor<'a> {
-    decrypter: Decrypter,
-    command: Command,
+const STATE_FILE_NAME: &str = ".rpgm-key";
+
+/// Length of the file prefix, which is read into memory when streaming. Key detection needs much less, it just must cover the first OGG page.
+const STREAM_PREFIX_LENGTH: u64 = 64 * 1024;
+
+/// Length of the file prefix, which `verify --dry-run` checks. Covers signatures and structure checks of all built-in types, but custom signatures at larger offsets aren't found in it.
+const PREDICT_PREFIX_LENGTH: u64 = 4 * 1024;
+
+/// Locations of `System.json` relative to the input directory and its ancestors, checked by encrypt preflight.
+const SYSTEM_JSON_LOCATIONS: &[&str] =
+    &["System.json", "data/System.json", "www/data/System.json"];
+
+const FILE_TYPES: &[FileType] = &[FileType::PNG, FileType::OGG, FileType::M4A];
+
+/// Serializes key detection across `--jobs` threads.
+static KEY_DETECTION: Mutex<()> = Mutex::new(());
+
+const fn encrypted_extension(
     engine: Engine,
-    output_dir: &'a Path,
-    input_dir: &'a Path,
-    file: Option<&'a PathBuf>,
-    global_key_set: bool,
+    file_type: FileType,
+) -> &'static str {
+    match (engine, file_type) {
+        (Engine::MV, FileType::PNG) => MV_PNG_EXT,
+        (Engine::MV, FileType::OGG) => MV_OGG_EXT,
+        (Engine::MV, FileType::M4A) => MV_M4A_EXT,
+        (Engine::MZ, FileType::PNG) => MZ_PNG_EXT,
+        (Engine::MZ, FileType::OGG) => MZ_OGG_EXT,
+        (Engine::MZ, FileType::M4A) => MZ_M4A_EXT,
+    }
 }
 
-impl<'a> Processor<'a> {
-    pub fn new(cli: &'a Cli) -> Result<Self, anyhow::Error> {
-        let mut decrypter = Decrypter::new();
-        let mut engine = Engine::MV;
+pub(crate) const fn decrypted_extension(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::PNG => PNG_EXT,
+        FileType::OGG => OGG_EXT,
+        FileType::M4A => M4A_EXT,
+    }
+}
+
+/// Reads the key and engine from the state file.
+fn read_state(
+    state_file: &Path,
+) -> Result<(Option<String>, Option<Engine>), anyhow::Error> {
+    let state: Value = from_str(&read_to_string(state_file)?)?;
+
+    let key = state["key"].as_str().map(str::to_owned);
+    let engine = state["engine"]
+        .as_str()
+        .and_then(|engine| Engine::from_str(engine, true).ok());
 
-        if let Some(file) = &cli.file {
-            if !file.is_file() {
-                bail!("--file argument expects file as its argument.");
+    Ok((key, engine))
+}
+
+/// Returns the key from `--key`, or derived from `--passphrase`.
+fn supplied_key(cli: &Cli) -> Option<String> {
+    if let Some(key) = &cli.key {
+        return Some(if cli.lenient_key {
+            normalize_key_length(key)
+        } else {
+            key.clone()
+        });
+    }
+
+    cli.passphrase
+        .as_deref()
+        .map(|passphrase| cli.kdf.derive_key(passphrase))
+}
+
+/// Checks that `--file` is an existing file or a supported URL, and that it's set for `extract-key` command.
+fn validate_file_arg(cli: &Cli) -> Result<(), anyhow::Error> {
+    if let Some(file) = &cli.file {
+        if is_url(file) {
+            if !cfg!(feature = "http") {
+                bail!(
+                    "Passing URLs to --file argument requires `http` feature to be enabled."
+                );
             }
-        } else if cli.command.is_extract_key() {
-            bail!("--file argument is not specified.");
+        } else if !file.is_file() {
+            bail!("--file argument expects file as its argument.");
         }
+    } else if cli.command.is_extract_key() {
+        bail!("--file argument is not specified.");
+    }
 
-        if let Some(key) = &cli.key {
-            decrypter.set_key_from_str(key)?;
-        } else if cli.command.is_encrypt() {
-            bail!("--key argument is not specified.");
-        }
+    if zip_archive(cli).is_some() && cli.command.is_encrypt() {
+        bail!(
+            "Zip archives can only be decrypted, verified or used to extract the key."
+        );
+    }
 
-        if let Some(eng) = cli.engine {
-            engine = eng;
-        } else if cli.command.is_encrypt() {
-            bail!("--engine argument is not specified.");
-        }
+    Ok(())
+}
 
-        let output_dir = cli.output_dir.as_ref().unwrap_or(&cli.input_dir);
+/// Returns whether the path is an existing zip archive.
+fn is_zip(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+        && path.is_file()
+}
 
-        Ok(Self {
-            decrypter,
-            command: cli.command,
-            engine,
-            output_dir,
-            input_dir: &cli.input_dir,
-            file: cli.file.as_ref(),
-            global_key_set: cli.key.is_some(),
+/// Returns zip archive, passed either to `--file` or `--input-dir`.
+fn zip_archive(cli: &Cli) -> Option<&Path> {
+    cli.file
+        .as_deref()
+        .or(Some(&cli.input_dir))
+        .filter(|path| is_zip(path))
+}
+
+/// Returns `--layout-rule` rules, followed by the built-in ones, if `--canonicalize-layout` is set.
+fn layout_rules(cli: &Cli) -> Option<Vec<LayoutRule>> {
+    cli.canonicalize_layout.then(|| {
+        cli.layout_rules
+            .iter()
+            .cloned()
+            .chain(
+                DEFAULT_LAYOUT
+                    .iter()
+                    .map(|&(pattern, folder)| LayoutRule::new(pattern, folder)),
+            )
+            .collect()
+    })
+}
+
+/// Returns `--suffix` to insert into names of conflicting outputs, if `--rename-on-conflict` is set.
+fn conflict_suffix(cli: &Cli) -> Option<String> {
+    cli.rename_on_conflict.then(|| {
+        cli.suffix.clone().unwrap_or_else(|| {
+            if cli.command.is_encrypt() {
+                String::from("encrypted")
+            } else {
+                String::from("decrypted")
+            }
         })
+    })
+}
+
+/// Inserts the suffix before the extension of the path: `file.png` => `file.suffix.png`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(suffix);
+
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
     }
 
-    fn process_file(
-        &mut self,
-        file: &Path,
-        extension: &str,
-    ) -> Result<(), anyhow::Error> {
-        let mut file_data = read(file)?;
+    path.with_file_name(file_name)
+}
 
-        let new_extension = if self.command.is_decrypt() {
-            let file_type = FileType::try_from(extension).unwrap();
+/// Returns `--output-dir`, or the default one: input directory, or directory named after the zip archive next to it.
+fn output_dir(cli: &Cli) -> PathBuf {
+    if let Some(output_dir) = &cli.output_dir {
+        output_dir.clone()
+    } else if let Some(archive) = zip_archive(cli) {
+        archive.with_extension("")
+    } else {
+        cli.input_dir.clone()
+    }
+}
 
-            // This is unlikely, but if we processing a directory when files have different encryption keys, we need to always reset the key
-            if !self.global_key_set {
-                self.decrypter.set_key_from_file(&file_data, file_type)?;
-            }
+/// Creates the output directory if it's missing, so the run doesn't fail after some files were already processed.
+fn prepare_output_dir(
+    output_dir: &Path,
+    dry_run: bool,
+) -> Result<(), anyhow::Error> {
+    if output_dir.is_dir() {
+        return Ok(());
+    }
 
-            let sliced =
-                self.decrypter.decrypt_in_place(&mut file_data, file_type)?;
+    if output_dir.exists() {
+        bail!(
+            "Output directory {} exists, but is not a directory.",
+            output_dir.display()
+        );
+    }
 
-            match extension {
-                MV_PNG_EXT | MZ_PNG_EXT => {
-                    if !sliced.starts_with(b"\x89PNG\r\n\x1a\n") {
-                        bail!(
-                            "Decrypted PNG file has invalid signature. Check if you supplied correct key in `--key` argument."
-                        );
-                    }
-                }
-                MV_OGG_EXT | MZ_OGG_EXT => {
-                    const OGG_SIGNATURE: &[u8] = b"OggS";
-                    if !sliced.starts_with(OGG_SIGNATURE) {
-                        bail!(
-                            "Decrypted OGG file has invalid signature. Check if you supplied correct key in `--key` argument."
-                        );
-                    }
-                }
-                MV_M4A_EXT | MZ_M4A_EXT => {
-                    if sliced.len() < 12 || &sliced[4..8] != b"ftyp" {
-                        bail!(
-                            "Decrypted M4A file has invalid signature. Check if you supplied correct key in `--key` argument."
-                        );
-                    }
-                }
-                _ => unreachable!(),
-            }
+    if !dry_run {
+        create_dir_all(output_dir).with_context(|| {
+            format!(
+                "Failed to create output directory {}",
+                output_dir.display()
+            )
+        })?;
+    }
 
-            match extension {
-                MV_PNG_EXT | MZ_PNG_EXT => PNG_EXT,
-                MV_OGG_EXT | MZ_OGG_EXT => OGG_EXT,
-                MV_M4A_EXT | MZ_M4A_EXT => M4A_EXT,
-                _ => unreachable!(),
-            }
-        } else {
-            self.decrypter.encrypt_in_place(&mut file_data)?;
-
-            match (self.engine, extension) {
-                (Engine::MV, PNG_EXT) => MV_PNG_EXT,
-                (Engine::MV, OGG_EXT) => MV_OGG_EXT,
-                (Engine::MV, M4A_EXT) => MV_M4A_EXT,
-                (Engine::MZ, PNG_EXT) => MZ_PNG_EXT,
-                (Engine::MZ, OGG_EXT) => MZ_OGG_EXT,
-                (Engine::MZ, M4A_EXT) => MZ_M4A_EXT,
-                _ => unreachable!(),
-            }
-        };
+    Ok(())
+}
+
+/// Sets the key from `System.json` inside the zip archive, or from its first encrypted file, if there's no `System.json`.
+///
+/// # Returns
+///
+/// - The set key, and whether it's from `System.json`.
+/// - Error, if the archive contains neither.
+fn set_key_from_zip<'d>(
+    decrypter: &'d mut Decrypter,
+    archive_path: &Path,
+) -> Result<(&'d str, bool), anyhow::Error> {
+    let mut archive = ZipArchive::new(File::open(archive_path)?)?;
+    let names = archive
+        .file_names()
+        .map(|name| name.map(Cow::into_owned))
+        .collect::<Result<Vec<_>, _>>()?;
 
-        let output_file_name =
-            PathBuf::from(unsafe { file.file_name().unwrap_unchecked() })
-                .with_extension(new_extension);
+    let system_json = names
+        .iter()
+        .filter(|name| {
+            Path::new(name).file_name() == Some(OsStr::new("System.json"))
+        })
+        // The game may have leftover `System.json` copies, the shallowest one is the actual one
+        .min_by_key(|name| name.len());
+
+    if let Some(name) = system_json {
+        let content = io::read_to_string(archive.by_name(name)?)?;
+        return Ok((set_key_from_system_json(decrypter, &content)?, true));
+    }
 
-        let output_file_path = self.output_dir.join(output_file_name);
+    let encrypted = names.iter().find(|name| {
+        Path::new(name)
+            .extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|extension| DECRYPT_EXTENSIONS.contains(&extension))
+    });
 
-        if self.command.is_decrypt() {
-            write(output_file_path, &file_data[HEADER_LENGTH..])?;
-        } else {
-            let mut output_data =
-                Vec::with_capacity(RPGM_HEADER.len() + file_data.len());
-            output_data.extend(RPGM_HEADER);
-            output_data.extend(file_data);
+    let Some(name) = encrypted else {
+        bail!(
+            "{} contains neither `System.json` nor RPG Maker encrypted files.",
+            archive_path.display()
+        );
+    };
+
+    let mut file_data = Vec::new();
+    archive.by_name(name)?.read_to_end(&mut file_data)?;
+
+    let extension = unsafe {
+        Path::new(name)
+            .extension()
+            .and_then(OsStr::to_str)
+            .unwrap_unchecked()
+    };
+
+    Ok((
+        set_key_from_file(
+            decrypter,
+            &file_data,
+            FileType::try_from(extension).unwrap(),
+        )?,
+        false,
+    ))
+}
+
+/// Returns the directory, which holds the input: parent of local `--file` or zip archive, or `--input-dir`.
+fn input_base_dir(cli: &Cli) -> &Path {
+    cli.file
+        .as_deref()
+        .filter(|file| !is_url(file))
+        .or_else(|| zip_archive(cli))
+        .and_then(Path::parent)
+        // Bare file name has empty parent
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(&cli.input_dir)
+}
+
+/// Finds `System.json` in `base_dir` or its ancestors, and parses it.
+fn find_system_json(
+    base_dir: &Path,
+) -> Result<Option<(PathBuf, Value)>, anyhow::Error> {
+    let base_dir = base_dir.canonicalize()?;
+
+    // Assets usually reside in `img`/`audio` subdirectories of the game
+    let Some(system_json) = base_dir
+        .ancestors()
+        .flat_map(|dir| {
+            SYSTEM_JSON_LOCATIONS
+                .iter()
+                .map(|location| dir.join(location))
+        })
+        .find(|path| path.is_file())
+    else {
+        return Ok(None);
+    };
+
+    let system_value = from_str(&read_to_string(&system_json)?)?;
+    Ok(Some((system_json, system_value)))
+}
 
-            write(output_file_path, output_data)?;
+/// Returns `encryptionKey` of `System.json`, found in `base_dir` or its ancestors, if it's a valid key.
+fn system_json_key(base_dir: &Path) -> Option<String> {
+    let (_, system_value) = find_system_json(base_dir).ok()??;
+
+    system_value["encryptionKey"]
+        .as_str()
+        .filter(|key| {
+            key.len() == KEY_STR_LENGTH
+                && key.bytes().all(|byte| byte.is_ascii_hexdigit())
+        })
+        .map(str::to_owned)
+}
+
+/// Detects the engine of the game, which `System.json` is found in `base_dir` or its ancestors.
+///
+/// # Returns
+///
+/// - [`Engine::MZ`] if `System.json` has `advanced` section, which was introduced in MZ.
+/// - [`Engine::MV`] if it doesn't.
+/// - [`None`] if there's no `System.json`.
+fn detect_engine(base_dir: &Path) -> Result<Option<Engine>, anyhow::Error> {
+    Ok(find_system_json(base_dir)?.map(|(_, system_value)| {
+        if system_value.get("advanced").is_some() {
+            Engine::MZ
+        } else {
+            Engine::MV
         }
+    }))
+}
 
-        Ok(())
+/// Warns, or errors if `strict` is set, when `System.json` found in `base_dir` or its ancestors expects another key than the supplied one.
+///
+/// Otherwise the game won't be able to decrypt the encrypted assets. Skipped if there's no `System.json`, or it's `updated` with the supplied key after encryption.
+fn verify_key_against_system_json(
+    base_dir: &Path,
+    key: &str,
+    strict: bool,
+    updated: Option<&Path>,
+) -> Result<(), anyhow::Error> {
+    let Some((system_json, system_value)) = find_system_json(base_dir)? else {
+        return Ok(());
+    };
+
+    // Path of found `System.json` is canonical, since the base directory is canonicalized
+    if updated.is_some_and(|updated| {
+        canonicalize(updated).is_ok_and(|updated| updated == system_json)
+    }) {
+        return Ok(());
     }
 
-    pub fn extract_key(&mut self) -> Result<(), anyhow::Error> {
-        let file_path = unsafe { self.file.unwrap_unchecked() };
-        let extension = unsafe {
-            file_path
-                .extension()
-                .and_then(OsStr::to_str)
-                .unwrap_unchecked()
-        };
-        let filename = unsafe { file_path.file_name().unwrap_unchecked() };
-        let system_value: Value;
+    let Some(expected_key) = system_value["encryptionKey"].as_str() else {
+        return Ok(());
+    };
 
-        let key = if filename == "System.json" {
-            let system_file_content = read_to_string(file_path)?;
+    if expected_key.eq_ignore_ascii_case(key) {
+        return Ok(());
+    }
 
-            system_value = from_str(&system_file_content)?;
-            system_value["encryptionKey"].as_str().unwrap()
-        } else if DECRYPT_EXTENSIONS.contains(&extension) {
-            let file_data = read(file_path)?;
-            self.decrypter.set_key_from_file(
-                &file_data,
-                FileType::try_from(extension).unwrap(),
-            )?
-        } else {
-            bail!(
-                "Key can be extracted only from `System.json` file or RPG Maker encrypted file."
-            );
-        };
+    let message = format!(
+        "--key {key} doesn't match `encryptionKey` {expected_key} in {}. The game won't be able to decrypt the encrypted files.",
+        system_json.display()
+    );
 
-        println!("Encryption key: {key}");
-        Ok(())
+    if strict {
+        bail!(message);
     }
 
-    pub fn process(&mut self) -> Result<(), anyhow::Error> {
-        if self.command.is_extract_key() {
-            self.extract_key()?;
+    warn!("{message}");
+    Ok(())
+}
+
+/// Detects the key from the encrypted file data.
+///
+/// [`Decrypter::set_key_from_file`] patches OGG serial number and M4A box size into process-global headers, so concurrent calls from worker threads would race on them.
+fn set_key_from_file<'d>(
+    decrypter: &'d mut Decrypter,
+    data: &[u8],
+    file_type: FileType,
+) -> Result<&'d str, asset_decrypter::Error> {
+    let _guard = KEY_DETECTION.lock().unwrap_or_else(PoisonError::into_inner);
+    decrypter.set_key_from_file(data, file_type)
+}
+
+/// Sets the key, checking that it's a hex string first, since the decrypter panics on other characters.
+fn set_hex_key(
+    decrypter: &mut Decrypter,
+    key: &str,
+) -> Result<(), anyhow::Error> {
+    if let Some(char) = key.chars().find(|char| !char.is_ascii_hexdigit()) {
+        bail!("Key must be a hex string, but `{key}` contains `{char}`.");
+    }
+
+    decrypter.set_key_from_str(key)?;
+    Ok(())
+}
+
+/// Parses `System.json` content, and sets the key from its `encryptionKey` field.
+///
+/// # Returns
+///
+/// - The set key.
+/// - Error, if `encryptionKey` is missing, null, empty or not a valid key.
+fn set_key_from_system_json<'d>(
+    decrypter: &'d mut Decrypter,
+    content: &str,
+) -> Result<&'d str, anyhow::Error> {
+    let system_value: Value = from_str(content)?;
+
+    let key = match system_value.get("encryptionKey") {
+        None => bail!(
+            "`System.json` has no `encryptionKey` field. The game's assets are likely not encrypted."
+        ),
+        Some(Value::Null) => bail!(
+            "`encryptionKey` in `System.json` is null. The game's assets are likely not encrypted."
+        ),
+        Some(Value::String(key)) if key.is_empty() => {
+            bail!("`encryptionKey` in `System.json` is empty.")
+        }
+        Some(Value::String(key)) => key,
+        Some(_) => bail!("`encryptionKey` in `System.json` is not a string."),
+    };
+
+    set_hex_key(decrypter, key)?;
+
+    // Key was just set
+    Ok(unsafe { decrypter.key().unwrap_unchecked() })
+}
+
+/// Zero-pads or truncates the key to [`KEY_STR_LENGTH`] characters, warning if it had invalid length.
+fn normalize_key_length(key: &str) -> String {
+    if key.len() == KEY_STR_LENGTH {
+        return key.to_owned();
+    }
+
+    warn!(
+        "--key has {} characters instead of {KEY_STR_LENGTH}, and was {} to fit. Processed files will likely be garbage, double-check your key.",
+        key.len(),
+        if key.len() < KEY_STR_LENGTH {
+            "zero-padded"
         } else {
-            let allowed_extensions = if self.command.is_encrypt() {
-                ENCRYPT_EXTENSIONS
-            } else {
-                DECRYPT_EXTENSIONS
-            };
+            "truncated"
+        }
+    );
 
-            if let Some(file) = &self.file {
-                if let Some(extension) =
-                    file.extension().and_then(OsStr::to_str)
-                    && allowed_extensions.contains(&extension)
-                {
-                    self.process_file(file, extension)?;
-                }
-            } else {
-                for entry in read_dir(self.input_dir)?.flatten() {
-                    let path = entry.path();
-
-                    if let Some(extension) =
-                        path.extension().and_then(OsStr::to_str)
-                        && allowed_extensions.contains(&extension)
-                    {
-                        self.process_file(&path, extension)?;
-                    }
-                }
+    let mut key: String = key.chars().take(KEY_STR_LENGTH).collect();
+
+    while key.len() < KEY_STR_LENGTH {
+        key.push('0');
+    }
+
+    key
+}
+
+fn parse_file_type(value: &str) -> Result<FileType, String> {
+    match value {
+        PNG_EXT => Ok(FileType::PNG),
+        OGG_EXT => Ok(FileType::OGG),
+        M4A_EXT => Ok(FileType::M4A),
+        _ => Err("expected `png`, `ogg` or `m4a`".into()),
+    }
+}
+
+fn parse_suffix(value: &str) -> Result<String, String> {
+    if value.is_empty() {
+        return Err("suffix must not be empty".into());
+    }
+
+    // Suffix is inserted into the file name, so it must not point elsewhere
+    if value.contains(['/', '\\']) {
+        return Err("suffix must not contain path separators".into());
+    }
+
+    Ok(value.to_owned())
+}
+
+fn parse_timestamp(value: &str) -> Result<SystemTime, String> {
+    if let Ok(secs) = value.parse::<u64>() {
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+
+    DateTime::parse_from_rfc3339(value)
+        .map(SystemTime::from)
+        .map_err(|_| "expected RFC 3339 timestamp or UNIX epoch seconds".into())
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    const HEX_CHARS: &[u8; 16] = b"0123456789abcdef";
+
+    let mut hex = String::with_capacity(bytes.len() * 2);
+
+    for &byte in bytes {
+        hex.push(HEX_CHARS[(byte >> 4) as usize] as char);
+        hex.push(HEX_CHARS[(byte & 0x0F) as usize] as char);
+    }
+
+    hex
+}
+
+/// Part of the source file after the read prefix, which is written to the output as is.
+enum SourceRest {
+    /// Opened source file, positioned after the prefix.
+    File(File),
+    /// Memory-mapped source file, and the length of the prefix.
+    Mapped(Mmap, usize),
+}
+
+/// Writes decrypted data, followed by the rest of the streamed or mapped source file if it's passed.
+///
+/// # Returns
+///
+/// - Number of written bytes.
+fn write_decrypted(
+    path: &Path,
+    data: &[u8],
+    rest: Option<SourceRest>,
+) -> Result<usize, anyhow::Error> {
+    write_atomically(path, |output_file| {
+        output_file.write_all(data)?;
+
+        Ok(match rest {
+            Some(SourceRest::File(mut rest)) => {
+                data.len() + copy(&mut rest, output_file)? as usize
             }
-        }
+            Some(SourceRest::Mapped(map, offset)) => {
+                output_file.write_all(&map[offset..])?;
+                data.len() + map.len() - offset
+            }
+            None => data.len(),
+        })
+    })
+}
 
-        Ok(())
+/// Writes the output to a temporary file in the same directory, and renames it into place, so interrupted writes never leave truncated outputs behind.
+///
+/// Temporary file is removed, if writing fails.
+fn write_atomically(
+    path: &Path,
+    write_output: impl FnOnce(&mut File) -> io::Result<usize>,
+) -> Result<usize, anyhow::Error> {
+    let mut temp_name = OsString::from(".");
+    temp_name.push(path.file_name().unwrap_or_default());
+    temp_name.push(format!(".{}.tmp", process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = File::create(&temp_path)
+        .and_then(|mut temp_file| {
+            let written = write_output(&mut temp_file)?;
+            temp_file.sync_all()?;
+            Ok(written)
+        })
+        .and_then(|written| {
+            rename(&temp_path, path)?;
+            Ok(written)
+        });
+
+    if result.is_err() {
+        let _ = remove_file(&temp_path);
     }
+
+    Ok(result?)
 }
 
-fn main() -> Result<()> {
-    let start_time = Instant::now();
+/// Sets modification and access times of the output to the ones of the source file.
+fn copy_times(source: &Path, output: &Path) -> Result<(), anyhow::Error> {
+    let source_metadata = metadata(source)?;
+    let times = FileTimes::new()
+        .set_modified(source_metadata.modified()?)
+        .set_accessed(source_metadata.accessed()?);
 
-    let cli = Cli::parse();
-    let mut processor = Processor::new(&cli)?;
-    processor.process()?;
+    File::options().write(true).open(output)?.set_times(times)?;
+    Ok(())
+}
+
+/// Deletes the source file, unless it's the same file as the just written output.
+fn remove_source(source: &Path, output: &Path) -> Result<(), anyhow::Error> {
+    // Both paths exist at this point, and canonical paths resolve `./` prefixes and symlinks
+    if canonicalize(source)? == canonicalize(output)? {
+        warn!(
+            "Not removing {}, since it was overwritten by the output.",
+            source.display()
+        );
+        return Ok(());
+    }
+
+    remove_file(source)?;
+    Ok(())
+}
+
+/// Returns whether the local file starts with RPG Maker encrypted file header.
+fn has_rpgm_header(path: &Path) -> bool {
+    let mut header = [0; RPGM_HEADER.len()];
+
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .is_ok_and(|()| header == RPGM_HEADER)
+}
+
+/// Returns whether the path is an HTTP(S) URL rather than a local path.
+fn is_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| {
+        path.starts_with("http://") || path.starts_with("https://")
+    })
+}
+
+/// Reads input file contents, downloading them if the path is an URL.
+fn read_input(path: &Path) -> Result<Vec<u8>, anyhow::Error> {
+    #[cfg(feature = "http")]
+    if is_url(path) {
+        return download(unsafe { path.to_str().unwrap_unchecked() });
+    }
+
+    Ok(read(path)?)
+}
+
+#[cfg(feature = "http")]
+fn download(url: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let mut response = ureq::get(url)
+        .call()
+        .with_context(|| format!("Failed to download {url}"))?;
+
+    response
+        .body_mut()
+        .with_config()
+        .limit(u64::MAX)
+        .read_to_vec()
+        .with_context(|| format!("Failed to download {url}"))
+}
+
+/// Compares decrypted data against the reference file, and prints whether they match or the first differing byte offset.
+fn compare_with_reference(
+    decrypted: &[u8],
+    file: &Path,
+    reference: &Path,
+) -> Result<(), anyhow::Error> {
+    let reference_data = read(reference)?;
+
+    let mismatch_offset = decrypted
+        .iter()
+        .zip(&reference_data)
+        .position(|(a, b)| a != b)
+        .or_else(|| {
+            (decrypted.len() != reference_data.len())
+                .then(|| decrypted.len().min(reference_data.len()))
+        });
+
+    if let Some(offset) = mismatch_offset {
+        println!(
+            "Mismatch: decrypted {} differs from {} at offset {offset}.",
+            file.display(),
+            reference.display()
+        );
+    } else {
+        println!(
+            "Match: decrypted {} is identical to {}.",
+            file.display(),
+            reference.display()
+        );
+    }
 
-    println!("Elapsed: {:.2}s", start_time.elapsed().as_secs_f32());
     Ok(())
 }
+
+/// Returns [`FileType`] of either encrypted or decrypted extension.
+fn file_type_of(extension: &str) -> Option<FileType> {
+    match extension {
+        PNG_EXT => Some(FileType::PNG),
+        OGG_EXT => Some(FileType::OGG),
+        M4A_EXT => Some(FileType::M4A),
+        _ => FileType::try_from(extension).ok(),
+    }
+}
+
+/// Message, sent from `--jobs` worker threads to the thread, that records outcomes.
+enum WorkerMessage<'p> {
+    Started(&'p Path),
+    Finished(&'p Path, Result<ProcessOutcome, anyhow::Error>, Duration),
+}
+
+/// Collects outcomes of processed files into the report, and emits the corresponding events.
+///
+/// Kept apart from the rest of [`Processor`], so outcomes of `--jobs` threads can be recorded while the threads still share the processor.
+#[derive(Default)]
+struct Recorder<'a> {
+    report: RunReport,
+    event_handler: Option<EventHandler<'a>>,
+    detected_engine: Option<Engine>,
+    decrypting: bool,
+    global_key_set: bool,
+    fail_fast: bool,
+}
+
+impl Recorder<'_> {
+    fn new(
+        cli: &Cli,
+        detected_engine: Option<Engine>,
+        global_key_set: bool,
+    ) -> Self {
+        Self {
+            report: RunReport::default(),
+            event_handler: None,
+            detected_engine,
+            decrypting: cli.command.is_decrypting(),
+            global_key_set,
+            // Single file has nothing to continue with, unlike archive entries
+            fail_fast: cli.fail_fast
+                || (cli.file.is_some() && zip_archive(cli).is_none()),
+        }
+    }
+
+    /// Records the outcome of processed file, or its failure.
+    ///
+    /// # Returns
+    ///
+    /// - Error of the file, if `--fail-fast` is set.
+    fn record_result(
+        &mut self,
+        file: &Path,
+        result: Result<ProcessOutcome, anyhow::Error>,
+        elapsed: Duration,
+    ) -> Result<(), anyhow::Error> {
+        match result {
+            Ok(outcome) => {
+                self.record(outcome, elapsed);
+                Ok(())
+            }
+            Err(err) if self.fail_fast => {
+                Err(err.context(file.display().to_string()))
+            }
+            Err(err) => {
+                error!("{}: {err}", file.display());
+                emit(
+                    &mut self.event_handler,
+                    Event::FileFailed {
+                        source: file,
+                        error: &err,
+                    },
+                );
+                self.report
+                    .failed
+                    .push((file.to_path_buf(), err.to_string()));
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds the outcome of processed file to the report, and emits the corresponding events.
+    fn record(&mut self, outcome: ProcessOutcome, elapsed: Duration) {
+        // Files with arbitrary extensions are processed with `--detect`
+        if self.decrypting
+            && let Some(engine) = outcome.engine
+        {
+            self.detected_engine = Some(engine);
+        }
+
+        if !self.global_key_set
+            && let Some(key) = &outcome.key
+            && !self.report.keys.contains(key)
+        {
+            emit(
+                &mut self.event_handler,
+                Event::KeyDetected {
+                    key,
+                    source: &outcome.source,
+                },
+            );
+        }
+
+        // Printed here rather than in worker threads, so lines of `--jobs` runs don't interleave
+        if outcome.status.is_planned() {
+            println!(
+                "{} -> {}",
+                outcome.source.display(),
+                outcome.output.display()
+            );
+        }
+
+        self.report.add(outcome, elapsed);
+
+        if let Some(record) = self.report.files.last() {
+            emit(
+                &mut self.event_handler,
+                Event::FileFinished(&record.outcome),
+            );
+        }
+    }
+}
+
+struct Processor<'a> {
+    decrypter: Decrypter,
+    command: Command,
+    engine: Engine,
+    output_dir: PathBuf,
+    input_dir: &'a Path,
+    file: Option<&'a PathBuf>,
+    validator: Box<dyn SignatureValidator>,
+    signatures: &'a [Signature],
+    global_key_set: bool,
+    /// Key from the game's `System.json`, tried for files, which the detected key doesn't decrypt.
+    system_json_key: Option<String>,
+    keep_invalid: bool,
+    recorder: Recorder<'a>,
+    state_file: Option<PathBuf>,
+    layout_rules: Option<Vec<LayoutRule>>,
+    print_key_only: bool,
+    format: OutputFormat,
+    compare_with: Option<&'a Path>,
+    since: Option<SystemTime>,
+    strict: bool,
+    no_op_on_match: bool,
+    skip_existing: bool,
+    recursive: bool,
+    jobs: usize,
+    parallel_chunk: usize,
+    detect: bool,
+    stream_threshold: u64,
+    update_system_json: Option<&'a Path>,
+    only: &'a [FileType],
+    engine_suffixed: HashSet<PathBuf>,
+    dry_run: bool,
+    predict: bool,
+    remove_source: bool,
+    from_list: Option<&'a Path>,
+    preserve_times: bool,
+    zip_archive: Option<&'a Path>,
+    mmap: bool,
+    follow_symlinks: bool,
+    conflict_suffix: Option<String>,
+}
+
+impl<'a> Processor<'a> {
+    pub fn new(cli: &'a Cli) -> Result<Self, anyhow::Error> {
+        let mut decrypter = Decrypter::new();
+        let mut engine = Engine::MV;
+
+        validate_file_arg(cli)?;
+
+        let state_file = cli
+            .state_file
+            .then(|| input_base_dir(cli).join(STATE_FILE_NAME));
+
+        let mut key = supplied_key(cli);
+        let mut detected_engine = cli.engine;
+
+        if !cli.command.is_extract_key()
+            && let Some(state_file) = &state_file
+            && state_file.is_file()
+        {
+            let (state_key, state_engine) = read_state(state_file)?;
+            key = key.or(state_key);
+            detected_engine = detected_engine.or(state_engine);
+        }
+
+        if let Some(key) = &key {
+            set_hex_key(&mut decrypter, key)?;
+        } else if cli.command.is_encrypt() {
+            bail!("--key argument is not specified.");
+        }
+
+        if detected_engine.is_none() && cli.command.is_encrypt() {
+            detected_engine = detect_engine(input_base_dir(cli))?;
+        }
+
+        if let Some(eng) = detected_engine {
+            engine = eng;
+        } else if cli.command.is_encrypt() {
+            bail!(
+                "--engine argument is not specified, and it couldn't be detected from `System.json`."
+            );
+        }
+
+        if cli.compare_with.is_some() && !cli.command.is_decrypt() {
+            bail!(
+                "--compare-with argument can only be used with decrypt command."
+            );
+        }
+
+        if cli.command.is_encrypt()
+            && let Some(key) = &key
+        {
+            verify_key_against_system_json(
+                input_base_dir(cli),
+                key,
+                cli.strict,
+                cli.update_system_json.as_deref(),
+            )?;
+        }
+
+        let output_dir = output_dir(cli);
+
+        // Other commands don't write anything into it
+        if cli.command.is_encrypt()
+            || (cli.command.is_decrypt() && cli.compare_with.is_none())
+        {
+            prepare_output_dir(&output_dir, cli.dry_run)?;
+        }
+
+        Ok(Self {
+            decrypter,
+            command: cli.command,
+            engine,
+            output_dir,
+            input_dir: &cli.input_dir,
+            file: cli.file.as_ref(),
+            validator: Box::new(CustomValidator::new(cli.signatures.clone())),
+            signatures: &cli.signatures,
+            global_key_set: key.is_some(),
+            system_json_key: (key.is_none() && cli.command.is_decrypting())
+                .then(|| system_json_key(input_base_dir(cli)))
+                .flatten(),
+            keep_invalid: cli.keep_invalid,
+            recorder: Recorder::new(cli, detected_engine, key.is_some()),
+            state_file,
+            layout_rules: layout_rules(cli),
+            // Only the key should get to stdout, when it's piped
+            print_key_only: cli.print_key_only
+                || (cli.quiet && !cli.format.is_json()),
+            format: cli.format,
+            compare_with: cli.compare_with.as_deref(),
+            since: cli.since,
+            strict: cli.strict,
+            no_op_on_match: cli.no_op_on_match,
+            skip_existing: cli.skip_existing,
+            recursive: cli.recursive,
+            jobs: cli.jobs,
+            parallel_chunk: cli.parallel_chunk,
+            detect: cli.detect && cli.command.is_decrypting(),
+            stream_threshold: cli.stream_threshold,
+            update_system_json: cli.update_system_json.as_deref(),
+            only: &cli.only,
+            engine_suffixed: HashSet::new(),
+            // `verify` and `--compare-with` don't write anything anyway
+            dry_run: cli.dry_run
+                && !cli.command.is_verify()
+                && cli.compare_with.is_none(),
+            predict: cli.dry_run && cli.command.is_verify(),
+            remove_source: cli.remove_source,
+            from_list: cli
+                .from_list
+                .as_deref()
+                .or(cli.input_list_stdin.then_some(Path::new("-"))),
+            preserve_times: cli.preserve_times,
+            zip_archive: zip_archive(cli),
+            mmap: cli.mmap,
+            follow_symlinks: cli.follow_symlinks,
+            conflict_suffix: conflict_suffix(cli),
+        })
+    }
+
+    /// Sets the callback, which receives [`Event`]s emitted during processing.
+    pub fn on_event(&mut self, handler: impl FnMut(Event<'_>) + Sync + 'a) {
+        self.recorder.event_handler = Some(Box::new(handler));
+    }
+
+    fn process_file(
+        &self,
+        decrypter: &mut Decrypter,
+        file: &Path,
+        extension: &str,
+    ) -> Result<ProcessOutcome, anyhow::Error> {
+        debug!(
+            "{} {}",
+            match self.command {
+                Command::Encrypt => "Encrypting",
+                Command::Verify => "Verifying",
+                _ => "Decrypting",
+            },
+            file.display()
+        );
+
+        if self.dry_run {
+            return Ok(self.plan_file(file, extension));
+        }
+
+        let (file_data, rest) = self.read_source(file)?;
+        self.process_data(decrypter, file, extension, file_data, rest)
+    }
+
+    /// Transforms the read file data, and writes the output.
+    ///
+    /// `rest` is the rest of the source file, which should be copied to the output after `file_data`, when it's streamed or mapped.
+    fn process_data(
+        &self,
+        decrypter: &mut Decrypter,
+        file: &Path,
+        extension: &str,
+        mut file_data: Vec<u8>,
+        rest: Option<SourceRest>,
+    ) -> Result<ProcessOutcome, anyhow::Error> {
+        let file_type =
+            self.resolve_file_type(decrypter, &file_data, file, extension)?;
+        let engine = if self.command.is_encrypt() {
+            Some(self.engine)
+        } else {
+            Engine::from_extension(extension)
+        };
+        let mut status = Status::Written;
+
+        if self.should_skip(file, &file_data, file_type)? {
+            return Ok(ProcessOutcome::unwritten(
+                file,
+                file,
+                file_type,
+                engine,
+                None,
+                Status::Skipped,
+            ));
+        }
+
+        let new_extension = if self.command.is_decrypting() {
+            match self.decrypt_data(
+                decrypter,
+                file,
+                &mut file_data,
+                file_type,
+                engine,
+            )? {
+                ControlFlow::Continue((extension, decrypted_status)) => {
+                    status = decrypted_status;
+                    extension
+                }
+                ControlFlow::Break(outcome) => return Ok(outcome),
+            }
+        } else {
+            decrypter.encrypt_in_place(&mut file_data)?;
+
+            encrypted_extension(self.engine, file_type)
+        };
+
+        let mut output_file_path = self.output_file_path(file, new_extension);
+
+        if let Some(parent) = output_file_path.parent() {
+            create_dir_all(parent)?;
+        }
+
+        let bytes = if self.command.is_decrypt() {
+            if status.is_invalid() {
+                output_file_path.as_mut_os_string().push(".invalid");
+            }
+
+            write_decrypted(
+                &output_file_path,
+                &file_data[HEADER_LENGTH..],
+                rest,
+            )?
+        } else if let Some(bytes) =
+            self.write_encrypted(&output_file_path, &file_data)?
+        {
+            bytes
+        } else {
+            status = Status::Skipped;
+            0
+        };
+
+        // Archive entries aren't files on disk
+        let local = !is_url(file) && self.zip_archive.is_none();
+
+        if self.preserve_times && !status.is_skipped() && local {
+            copy_times(file, &output_file_path)?;
+        }
+
+        // Invalid outputs are kept for inspection, and sources of them may be needed to retry with the correct key
+        if self.remove_source && status.is_written() && local {
+            remove_source(file, &output_file_path)?;
+        }
+
+        Ok(ProcessOutcome {
+            source: file.to_path_buf(),
+            output: output_file_path,
+            file_type,
+            engine,
+            key: decrypter.key().map(str::to_owned),
+            bytes: bytes as u64,
+            status,
+        })
+    }
+
+    /// Computes the output path of the file in `--dry-run` mode, without reading it.
+    ///
+    /// Decrypted extension is the default one for the file type, since actual extension is only known after validating decrypted signature.
+    fn plan_file(&self, file: &Path, extension: &str) -> ProcessOutcome {
+        let Some(file_type) = file_type_of(extension) else {
+            warn!(
+                "Skipping {}, its type is only detected when processing.",
+                file.display()
+            );
+
+            return ProcessOutcome::unwritten(
+                file,
+                file,
+                FileType::PNG,
+                None,
+                None,
+                Status::Skipped,
+            );
+        };
+
+        let (engine, new_extension) = if self.command.is_encrypt() {
+            (
+                Some(self.engine),
+                encrypted_extension(self.engine, file_type),
+            )
+        } else {
+            (
+                Engine::from_extension(extension),
+                decrypted_extension(file_type),
+            )
+        };
+
+        ProcessOutcome::unwritten(
+            file,
+            &self.output_file_path(file, new_extension),
+            file_type,
+            engine,
+            None,
+            Status::Planned,
+        )
+    }
+
+    /// Decrypts the file data in place, and checks its signature.
+    ///
+    /// # Returns
+    ///
+    /// - [`ControlFlow::Continue`] with extension and status of the output, if it should be written.
+    /// - [`ControlFlow::Break`] with the outcome, if nothing should be written, because the file was compared or verified.
+    fn decrypt_data(
+        &self,
+        decrypter: &mut Decrypter,
+        file: &Path,
+        file_data: &mut [u8],
+        file_type: FileType,
+        engine: Option<Engine>,
+    ) -> Result<ControlFlow<ProcessOutcome, (&str, Status)>, anyhow::Error>
+    {
+        // Usually a result of decrypting already decrypted files, slicing the header off them would corrupt the output
+        if !file_data.starts_with(RPGM_HEADER) {
+            bail!(
+                "{} does not appear to be RPG Maker encrypted file, since it doesn't start with RPGMV header. It may be already decrypted.",
+                file.display()
+            );
+        }
+
+        // File that consists only of the fake header and encrypted source header has no actual content to decrypt, and would fail signature check with a misleading message
+        if file_data.len() <= RPGM_HEADER.len() + HEADER_LENGTH {
+            bail!(
+                "{} has no payload after header. The file is likely truncated.",
+                file.display()
+            );
+        }
+
+        // This is unlikely, but if we processing a directory when files have different encryption keys, we need to always reset the key
+        if !self.global_key_set {
+            set_key_from_file(decrypter, file_data, file_type)?;
+        }
+
+        let encrypted_header =
+            file_data[..RPGM_HEADER.len() + HEADER_LENGTH].to_vec();
+        decrypter.decrypt_in_place(file_data, file_type)?;
+
+        let mut verified = verify_decrypted(
+            &file_data[HEADER_LENGTH..],
+            file_type,
+            self.validator.as_ref(),
+        );
+
+        if verified.is_err()
+            && !self.global_key_set
+            && let Some(extension) = self.retry_with_system_json_key(
+                decrypter,
+                file_data,
+                &encrypted_header,
+                file_type,
+            )?
+        {
+            verified = Ok(extension);
+        }
+
+        let sliced = &file_data[HEADER_LENGTH..];
+
+        if let Some(reference) = self.compare_with {
+            compare_with_reference(sliced, file, reference)?;
+
+            return Ok(ControlFlow::Break(ProcessOutcome::unwritten(
+                file,
+                reference,
+                file_type,
+                engine,
+                decrypter.key(),
+                Status::Compared,
+            )));
+        }
+
+        if self.command.is_verify() {
+            if let Err(err) = &verified {
+                warn!("{}: {err}", file.display());
+            }
+
+            return Ok(ControlFlow::Break(ProcessOutcome::unwritten(
+                file,
+                file,
+                file_type,
+                engine,
+                decrypter.key(),
+                if verified.is_ok() {
+                    Status::Verified
+                } else {
+                    Status::Invalid
+                },
+            )));
+        }
+
+        match verified {
+            Ok(extension) => {
+                Ok(ControlFlow::Continue((extension, Status::Written)))
+            }
+            Err(err) if self.keep_invalid => {
+                warn!("{}: {err}", file.display());
+                Ok(ControlFlow::Continue((
+                    decrypted_extension(file_type),
+                    Status::Invalid,
+                )))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Decrypts the file data again with the key from `System.json`, after the detected key produced invalid signature.
+    ///
+    /// Detected key always turns the header into the signature of `file_type`, so files with other content, such as mislabeled MP3 or WebP, only decrypt correctly with the actual key of the game.
+    ///
+    /// # Returns
+    ///
+    /// - Extension of the output, if data decrypted with `System.json` key has valid signature. Decrypter keeps that key then.
+    /// - [`None`] otherwise, leaving the data and the key as they were.
+    fn retry_with_system_json_key(
+        &self,
+        decrypter: &mut Decrypter,
+        file_data: &mut [u8],
+        encrypted_header: &[u8],
+        file_type: FileType,
+    ) -> Result<Option<&str>, anyhow::Error> {
+        let Some(key) = &self.system_json_key else {
+            return Ok(None);
+        };
+
+        let Some(detected_key) = decrypter
+            .key()
+            .filter(|detected_key| !detected_key.eq_ignore_ascii_case(key))
+            .map(str::to_owned)
+        else {
+            return Ok(None);
+        };
+
+        let header_range = ..encrypted_header.len();
+        let decrypted_header = file_data[header_range].to_vec();
+
+        file_data[header_range].copy_from_slice(encrypted_header);
+        decrypter.set_key_from_str(key)?;
+        decrypter.decrypt_in_place(file_data, file_type)?;
+
+        if let Some(extension) = self
+            .validator
+            .validate(&file_data[HEADER_LENGTH..], file_type)
+        {
+            return Ok(Some(extension));
+        }
+
+        file_data[header_range].copy_from_slice(&decrypted_header);
+        decrypter.set_key_from_str(&detected_key)?;
+        Ok(None)
+    }
+
+    /// Returns whether the read file should be skipped, because it's empty or its type doesn't pass `--only` filter.
+    fn should_skip(
+        &self,
+        file: &Path,
+        data: &[u8],
+        file_type: FileType,
+    ) -> Result<bool, anyhow::Error> {
+        // Type of files without known extension is only known after `--detect`
+        if !self.allows_type(file_type) {
+            return Ok(true);
+        }
+
+        // Usually a result of truncated download
+        if data.is_empty() {
+            if self.strict {
+                bail!("{} is empty.", file.display());
+            }
+
+            warn!("Skipping empty file {}.", file.display());
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Returns whether the file type passes `--only` filter.
+    fn allows_type(&self, file_type: FileType) -> bool {
+        self.only.is_empty() || self.only.contains(&file_type)
+    }
+
+    /// Returns the type of the file, detected from its content with `--detect`, or from its extension.
+    fn resolve_file_type(
+        &self,
+        decrypter: &mut Decrypter,
+        data: &[u8],
+        file: &Path,
+        extension: &str,
+    ) -> Result<FileType, anyhow::Error> {
+        let detected_type = if self.detect {
+            detect_file_type(data, decrypter)
+        } else {
+            None
+        };
+
+        let Some(file_type) = detected_type.or_else(|| file_type_of(extension))
+        else {
+            bail!("Couldn't detect type of {}.", file.display());
+        };
+
+        Ok(file_type)
+    }
+
+    /// Writes encrypted data, prepended with RPG Maker header.
+    ///
+    /// # Returns
+    ///
+    /// - Number of written bytes.
+    /// - [`None`] if `--no-op-on-match` is set, and the existing output already matches.
+    fn write_encrypted(
+        &self,
+        path: &Path,
+        data: &[u8],
+    ) -> Result<Option<usize>, anyhow::Error> {
+        let mut output_data =
+            Vec::with_capacity(RPGM_HEADER.len() + data.len());
+        output_data.extend(RPGM_HEADER);
+        output_data.extend(data);
+
+        // Encryption is deterministic, so matching output means the file is already encrypted with the current key
+        if self.no_op_on_match
+            && read(path).is_ok_and(|existing| existing == output_data)
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(write_atomically(path, |output_file| {
+            output_file.write_all(&output_data)?;
+            Ok(output_data.len())
+        })?))
+    }
+
+    /// Reads the file contents.
+    ///
+    /// Local files larger than `--stream-threshold` are decrypted in a streaming way: since only the header is encrypted, just the prefix of the file is read, and opened file is returned to copy the rest from. With `--mmap`, decrypted files are mapped instead. `verify` command reads only the prefix of every local file, and `verify --dry-run` an even shorter one.
+    fn read_source(
+        &self,
+        file: &Path,
+    ) -> Result<(Vec<u8>, Option<SourceRest>), anyhow::Error> {
+        if is_url(file) {
+            return Ok((read_input(file)?, None));
+        }
+
+        // Comparison needs the whole decrypted file
+        let partial = self.command.is_decrypt() && self.compare_with.is_none();
+        let file_length = metadata(file)?.len();
+
+        // Empty files can't be mapped
+        if partial && self.mmap && file_length > 0 {
+            let source = File::open(file)?;
+
+            // SAFETY: Source files aren't expected to be modified during processing, modifying them would corrupt only the output
+            let map = unsafe { Mmap::map(&source)? };
+            let prefix_length = map.len().min(STREAM_PREFIX_LENGTH as usize);
+
+            return Ok((
+                map[..prefix_length].to_vec(),
+                Some(SourceRest::Mapped(map, prefix_length)),
+            ));
+        }
+
+        let stream = partial && file_length > self.stream_threshold;
+
+        if !stream && !self.command.is_verify() {
+            return Ok((read_input(file)?, None));
+        }
+
+        let prefix_length = if self.predict {
+            PREDICT_PREFIX_LENGTH
+        } else {
+            STREAM_PREFIX_LENGTH
+        };
+
+        let mut source = File::open(file)?;
+        let mut prefix = Vec::with_capacity(prefix_length as usize);
+        (&mut source).take(prefix_length).read_to_end(&mut prefix)?;
+
+        Ok((prefix, stream.then_some(SourceRest::File(source))))
+    }
+
+    /// Processes files in the directory, descending into subdirectories if `--recursive` is set.
+    fn process_dir(&mut self, dir: &Path) -> Result<(), anyhow::Error> {
+        let mut candidates = Vec::new();
+        let mut visited = HashSet::new();
+
+        if self.follow_symlinks {
+            visited.insert(canonicalize(dir)?);
+        }
+
+        // Paths are compared canonicalized, since the same directory may be passed differently, e.g. `-i in -o ./in/out`. Output directory only doesn't exist in `--dry-run`, and then there's nothing to exclude.
+        let output_dir =
+            canonicalize(&self.output_dir).ok().filter(|output_dir| {
+                canonicalize(dir).is_ok_and(|dir| dir != *output_dir)
+            });
+
+        self.collect_candidates(
+            dir,
+            output_dir.as_deref(),
+            &mut candidates,
+            &mut visited,
+        )?;
+        self.process_candidates(candidates)
+    }
+
+    /// Processes the candidates, which outputs pass `--skip-existing` filter.
+    ///
+    /// Engine suffixes of colliding outputs are resolved first, so existing outputs are checked at their final paths.
+    fn process_candidates(
+        &mut self,
+        candidates: Vec<(PathBuf, String)>,
+    ) -> Result<(), anyhow::Error> {
+        if self.command.is_decrypting() {
+            self.check_mixed_engines(&candidates);
+        }
+
+        let candidates: Vec<_> = candidates
+            .into_iter()
+            .filter(|(path, extension)| {
+                self.passes_existing_filter(path, extension)
+            })
+            .collect();
+
+        self.process_batch(&candidates)
+    }
+
+    /// Warns about directories, that contain both MV and MZ encrypted files, which usually indicates a packaging mistake.
+    ///
+    /// Files with the same name under both conventions would produce the same output, so they're marked to get engine suffix in output name, e.g. `file.mv.png` and `file.mz.png`.
+    fn check_mixed_engines(&mut self, candidates: &[(PathBuf, String)]) {
+        let mut dir_engines: BTreeMap<&Path, BTreeSet<&str>> = BTreeMap::new();
+        let mut outputs: HashMap<PathBuf, Vec<&Path>> = HashMap::new();
+
+        for (path, extension) in candidates {
+            let (Some(engine), Some(file_type)) =
+                (Engine::from_extension(extension), file_type_of(extension))
+            else {
+                continue;
+            };
+
+            if let Some(dir) = path.parent() {
+                dir_engines.entry(dir).or_default().insert(engine.as_str());
+            }
+
+            outputs
+                .entry(path.with_extension(decrypted_extension(file_type)))
+                .or_default()
+                .push(path);
+        }
+
+        for (dir, engines) in dir_engines {
+            if engines.len() > 1 {
+                warn!(
+                    "{} contains both MV and MZ encrypted files. It's likely merged from different sources.",
+                    dir.display()
+                );
+            }
+        }
+
+        self.engine_suffixed.extend(
+            outputs
+                .into_values()
+                .filter(|sources| sources.len() > 1)
+                .flatten()
+                .map(Path::to_path_buf),
+        );
+    }
+
+    /// Collects files with allowed extensions, that pass `--since` filter, along with their extensions.
+    ///
+    /// `output_dir` is canonical path of the output directory, which isn't descended into, so our own outputs aren't processed, when output directory is inside the input one.
+    ///
+    /// Symlinked files are processed, but symlinked directories are skipped, unless `--follow-symlinks` is set, since they may point back up the tree. With it, canonical paths of followed files and directories are tracked in `visited`, so symlinks pointing back up the tree don't cause infinite recursion, and the same file isn't processed twice.
+    fn collect_candidates(
+        &mut self,
+        dir: &Path,
+        output_dir: Option<&Path>,
+        candidates: &mut Vec<(PathBuf, String)>,
+        visited: &mut HashSet<PathBuf>,
+    ) -> Result<(), anyhow::Error> {
+        for entry in read_dir(dir)?.flatten() {
+            let path = entry.path();
+
+            if self.follow_symlinks {
+                let Ok(canonical_path) = canonicalize(&path) else {
+                    warn!("Skipping broken symlink {}.", path.display());
+                    self.recorder.report.skipped += 1;
+                    continue;
+                };
+
+                if !visited.insert(canonical_path) {
+                    debug!("Skipping already visited {}.", path.display());
+                    self.recorder.report.skipped += 1;
+                    continue;
+                }
+            } else if self.recursive
+                && path.is_dir()
+                && entry.file_type().is_ok_and(|kind| kind.is_symlink())
+            {
+                warn!(
+                    "Skipping symlinked directory {}. Use --follow-symlinks to descend into it.",
+                    path.display()
+                );
+                self.recorder.report.skipped += 1;
+                continue;
+            }
+
+            if !path.is_dir() {
+                if let Some(extension) = self.candidate_extension(&path)? {
+                    candidates.push((path, extension));
+                }
+            } else if self.recursive
+                && output_dir.is_none_or(|output_dir| {
+                    canonicalize(&path).is_ok_and(|path| path != output_dir)
+                })
+            {
+                self.collect_candidates(
+                    &path, output_dir, candidates, visited,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Processes the files across `--jobs` threads, and adds their outcomes to the report in the order they finish.
+    fn process_batch(
+        &mut self,
+        candidates: &[(PathBuf, String)],
+    ) -> Result<(), anyhow::Error> {
+        if self.jobs == 1 {
+            for (path, extension) in candidates {
+                self.process_timed(path, extension)?;
+            }
+
+            return Ok(());
+        }
+
+        let global_key = self
+            .decrypter
+            .key()
+            .filter(|_| self.global_key_set)
+            .map(str::to_owned);
+
+        // Decrypter is mutated when the key is detected from each file, so every thread needs its own. Detection itself is still serialized by `set_key_from_file`, since the library keeps part of its state in globals
+        let new_decrypter = || {
+            let mut decrypter = Decrypter::new();
+
+            if let Some(key) = &global_key {
+                // Key was already validated in `Processor::new`
+                let _ = decrypter.set_key_from_str(key);
+            }
+
+            decrypter
+        };
+
+        let pool = ThreadPoolBuilder::new().num_threads(self.jobs).build()?;
+        let mut recorder = take(&mut self.recorder);
+        let (sender, receiver) = channel();
+        let stop = AtomicBool::new(false);
+        let this = &*self;
+
+        // Outcomes are recorded as they arrive, so events reach the handler while the rest of the files are still processed
+        let result = thread::scope(|scope| {
+            scope.spawn(|| {
+                pool.install(|| {
+                    candidates
+                        .par_iter()
+                        .with_min_len(this.parallel_chunk)
+                        .for_each_init(
+                            || (new_decrypter(), sender.clone()),
+                            |(decrypter, sender), (path, extension)| {
+                                // Files, that already started, still finish, but no new ones are started after `--fail-fast` failure
+                                if stop.load(Ordering::Relaxed) {
+                                    return;
+                                }
+
+                                let _ =
+                                    sender.send(WorkerMessage::Started(path));
+
+                                let start_time = Instant::now();
+                                let result = this
+                                    .process_file(decrypter, path, extension);
+
+                                let _ = sender.send(WorkerMessage::Finished(
+                                    path,
+                                    result,
+                                    start_time.elapsed(),
+                                ));
+                            },
+                        );
+                });
+
+                // Receiver stops waiting, when the last sender is dropped
+                drop(sender);
+            });
+
+            for message in receiver {
+                match message {
+                    WorkerMessage::Started(path) => emit(
+                        &mut recorder.event_handler,
+                        Event::FileStarted(path),
+                    ),
+                    WorkerMessage::Finished(path, result, elapsed) => {
+                        if let Err(err) =
+                            recorder.record_result(path, result, elapsed)
+                        {
+                            stop.store(true, Ordering::Relaxed);
+                            return Err(err);
+                        }
+                    }
+                }
+            }
+
+            Ok(())
+        });
+
+        self.recorder = recorder;
+
+        // Keep the last detected key for the state file
+        if !self.global_key_set
+            && let Some(key) = self
+                .recorder
+                .report
+                .files
+                .iter()
+                .rev()
+                .find_map(|record| record.outcome.key.as_deref())
+        {
+            self.decrypter.set_key_from_str(key)?;
+        }
+
+        result
+    }
+
+    /// Returns the extension of the file, if it's allowed and passes `--since` filter.
+    fn candidate_extension(
+        &mut self,
+        path: &Path,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let allowed_extensions = if self.command.is_encrypt() {
+            ENCRYPT_EXTENSIONS
+        } else {
+            DECRYPT_EXTENSIONS
+        };
+
+        let extension =
+            path.extension().and_then(OsStr::to_str).unwrap_or_default();
+
+        // Type of other encrypted files is detected from their content
+        let allowed = (allowed_extensions.contains(&extension)
+            || (self.detect && has_rpgm_header(path)))
+            && file_type_of(extension)
+                .is_none_or(|file_type| self.allows_type(file_type));
+
+        if !allowed {
+            self.recorder.report.skipped += 1;
+        } else if self.passes_since_filter(path)? {
+            return Ok(Some(extension.to_owned()));
+        }
+
+        Ok(None)
+    }
+
+    /// Processes the file if it has an allowed extension and passes the filters.
+    fn process_candidate(&mut self, path: &Path) -> Result<(), anyhow::Error> {
+        if let Some(extension) = self.candidate_extension(path)?
+            && self.passes_existing_filter(path, &extension)
+        {
+            self.process_timed(path, &extension)?;
+        }
+
+        Ok(())
+    }
+
+    /// Processes the file, and adds its outcome along with processing time to the report.
+    fn process_timed(
+        &mut self,
+        file: &Path,
+        extension: &str,
+    ) -> Result<(), anyhow::Error> {
+        emit(&mut self.recorder.event_handler, Event::FileStarted(file));
+
+        let mut decrypter = take(&mut self.decrypter);
+        let start_time = Instant::now();
+        let result = self.process_file(&mut decrypter, file, extension);
+        let elapsed = start_time.elapsed();
+        self.decrypter = decrypter;
+
+        self.recorder.record_result(file, result, elapsed)
+    }
+
+    /// Decrypts encrypted entries of the zip archive in memory, preserving their paths inside the archive in the output directory.
+    fn process_zip(
+        &mut self,
+        archive_path: &Path,
+    ) -> Result<(), anyhow::Error> {
+        let mut archive = ZipArchive::new(File::open(archive_path)?)?;
+
+        // Entries aren't decompressed here, just their names are needed to mark colliding MV and MZ outputs
+        let mut entries = Vec::new();
+
+        for index in 0..archive.len() {
+            let entry = archive.by_index_raw(index)?;
+
+            if let Some(name) = entry.enclosed_name()
+                && let Some(extension) =
+                    name.extension().and_then(OsStr::to_str)
+            {
+                entries.push((archive_path.join(&name), extension.to_owned()));
+            }
+        }
+
+        self.check_mixed_engines(&entries);
+
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index)?;
+
+            // Entries with absolute paths or `..` components would escape the output directory
+            let Some(name) = entry.enclosed_name().filter(|_| !entry.is_dir())
+            else {
+                continue;
+            };
+
+            let extension = name
+                .extension()
+                .and_then(OsStr::to_str)
+                .unwrap_or_default()
+                .to_owned();
+
+            let known = DECRYPT_EXTENSIONS.contains(&extension.as_str());
+            let mut file_data = Vec::new();
+
+            // Type of other encrypted entries is detected from their content. Read header stays in `file_data`, and the rest of the entry is appended to it
+            if !known && self.detect {
+                (&mut entry)
+                    .take(RPGM_HEADER.len() as u64)
+                    .read_to_end(&mut file_data)?;
+            }
+
+            let allowed = (known || (self.detect && file_data == RPGM_HEADER))
+                && file_type_of(&extension)
+                    .is_none_or(|file_type| self.allows_type(file_type));
+
+            if !allowed {
+                self.recorder.report.skipped += 1;
+                continue;
+            }
+
+            let path = archive_path.join(name);
+
+            if !self.passes_existing_filter(&path, &extension) {
+                continue;
+            }
+
+            emit(&mut self.recorder.event_handler, Event::FileStarted(&path));
+
+            let mut decrypter = take(&mut self.decrypter);
+            let start_time = Instant::now();
+            let result = if self.dry_run {
+                Ok(self.plan_file(&path, &extension))
+            } else {
+                let length = if self.predict {
+                    PREDICT_PREFIX_LENGTH
+                } else {
+                    u64::MAX
+                };
+
+                (&mut entry)
+                    .take(length)
+                    .read_to_end(&mut file_data)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|_| {
+                        self.process_data(
+                            &mut decrypter,
+                            &path,
+                            &extension,
+                            file_data,
+                            None,
+                        )
+                    })
+            };
+            let elapsed = start_time.elapsed();
+            self.decrypter = decrypter;
+
+            self.recorder.record_result(&path, result, elapsed)?;
+        }
+
+        Ok(())
+    }
+
+    /// Computes the output path for the file with the new extension.
+    ///
+    /// Directories of the path are created only when the output is written, so files that end up skipped don't leave empty directories behind.
+    ///
+    /// With `--rename-on-conflict`, existing file at the path gets the suffixed path instead, e.g. `file.decrypted.png`, or numbered one, if the suffixed path exists too, e.g. `file.decrypted.2.png`.
+    fn output_file_path(&self, file: &Path, new_extension: &str) -> PathBuf {
+        let mut output_file_name =
+            PathBuf::from(unsafe { file.file_name().unwrap_unchecked() });
+
+        if self.engine_suffixed.contains(file)
+            && let Some(engine) = file
+                .extension()
+                .and_then(OsStr::to_str)
+                .and_then(Engine::from_extension)
+        {
+            output_file_name
+                .set_extension(format!("{}.{new_extension}", engine.as_str()));
+        } else {
+            output_file_name.set_extension(new_extension);
+        }
+
+        let mut output_file_path = self.output_dir.clone();
+
+        if let Some(rules) = &self.layout_rules
+            && let Some(folder) = output_file_name
+                .to_str()
+                .and_then(|name| canonical_folder(rules, name))
+        {
+            output_file_path.push(folder);
+        } else if let Some(mirror_base) = self
+            .zip_archive
+            .or(self.recursive.then_some(self.input_dir))
+            && let Some(relative_dir) = file
+                .parent()
+                .and_then(|parent| parent.strip_prefix(mirror_base).ok())
+                .filter(|relative_dir| !relative_dir.as_os_str().is_empty())
+        {
+            output_file_path.push(relative_dir);
+        }
+
+        output_file_path.push(output_file_name);
+
+        if let Some(suffix) = &self.conflict_suffix
+            && output_file_path.exists()
+        {
+            let mut renamed = with_suffix(&output_file_path, suffix);
+            let mut index = 2;
+
+            while renamed.exists() {
+                renamed = with_suffix(
+                    &output_file_path,
+                    &format!("{suffix}.{index}"),
+                );
+                index += 1;
+            }
+
+            info!(
+                "{} already exists, writing {} instead.",
+                output_file_path.display(),
+                renamed.display()
+            );
+            return renamed;
+        }
+
+        output_file_path
+    }
+
+    pub fn extract_key(&mut self) -> Result<(), anyhow::Error> {
+        let file_path = unsafe { self.file.unwrap_unchecked() };
+        let extension = unsafe {
+            file_path
+                .extension()
+                .and_then(OsStr::to_str)
+                .unwrap_unchecked()
+        };
+        let filename = unsafe { file_path.file_name().unwrap_unchecked() };
+
+        let mut from_system_json = filename == "System.json";
+
+        let key = if from_system_json {
+            let system_file_content =
+                String::from_utf8(read_input(file_path)?)?;
+
+            set_key_from_system_json(&mut self.decrypter, &system_file_content)?
+        } else if let Some(archive) = self.zip_archive {
+            let (key, from_zip_system_json) =
+                set_key_from_zip(&mut self.decrypter, archive)?;
+            from_system_json = from_zip_system_json;
+            key
+        } else if DECRYPT_EXTENSIONS.contains(&extension) {
+            let file_data = read_input(file_path)?;
+            set_key_from_file(
+                &mut self.decrypter,
+                &file_data,
+                FileType::try_from(extension).unwrap(),
+            )?
+        } else {
+            bail!(
+                "Key can be extracted only from `System.json` file, RPG Maker encrypted file or zip archive."
+            );
+        };
+
+        emit(
+            &mut self.recorder.event_handler,
+            Event::KeyDetected {
+                key,
+                source: file_path,
+            },
+        );
+
+        if self.print_key_only {
+            println!("{key}");
+        } else if self.format.is_json() {
+            let output = json!({
+                "key": key,
+                "source": if from_system_json {
+                    "system_json"
+                } else {
+                    "encrypted_file"
+                },
+                "file": file_path.to_string_lossy(),
+            });
+
+            println!("{output}");
+        } else {
+            println!("Encryption key: {key}");
+        }
+
+        Ok(())
+    }
+
+    pub fn process(&mut self) -> Result<(), anyhow::Error> {
+        let start_time = Instant::now();
+
+        if let Command::Types { json } = self.command {
+            self.list_types(json || self.format.is_json())?;
+        } else if self.command.is_extract_key() {
+            self.extract_key()?;
+        } else if let Some(list) = self.from_list {
+            if list == Path::new("-") {
+                self.process_list(stdin().lock())?;
+            } else {
+                self.process_list(BufReader::new(File::open(list)?))?;
+            }
+        } else if let Some(archive) = self.zip_archive {
+            self.process_zip(archive)?;
+        } else if let Some(file) = self.file {
+            self.process_candidate(file)?;
+        } else {
+            self.process_dir(self.input_dir)?;
+        }
+
+        if let Some(state_file) = &self.state_file
+            && !self.command.is_types()
+            && !self.dry_run
+        {
+            self.write_state(state_file)?;
+        }
+
+        if let Some(system_json) = self.update_system_json
+            && self.command.is_encrypt()
+            && !self.dry_run
+        {
+            self.write_system_json(system_json)?;
+        }
+
+        self.recorder.report.elapsed = start_time.elapsed();
+
+        if !self.command.is_extract_key() && !self.command.is_types() {
+            emit(
+                &mut self.recorder.event_handler,
+                Event::BatchDone(&self.recorder.report),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Processes newline-separated file paths from the list, resolving relative ones against the input directory.
+    ///
+    /// Listed files are processed together like the ones of a directory, so outputs of MV and MZ files with the same name get engine suffixes.
+    fn process_list(
+        &mut self,
+        list: impl BufRead,
+    ) -> Result<(), anyhow::Error> {
+        let mut candidates = Vec::new();
+
+        for line in list.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let path = self.input_dir.join(line);
+
+            if !path.is_file() {
+                warn!("Skipping missing file {}.", path.display());
+                self.recorder.report.skipped += 1;
+                continue;
+            }
+
+            if let Some(extension) = self.candidate_extension(&path)? {
+                candidates.push((path, extension));
+            }
+        }
+
+        self.process_candidates(candidates)
+    }
+
+    /// Prints supported file types and their extensions, including custom `--signature` additions.
+    fn list_types(&self, json: bool) -> Result<(), anyhow::Error> {
+        if json {
+            let types: Vec<Value> = FILE_TYPES
+                .iter()
+                .map(|&file_type| {
+                    json!({
+                        "type": file_type.to_string(),
+                        "decrypted": decrypted_extension(file_type),
+                        "mv": encrypted_extension(Engine::MV, file_type),
+                        "mz": encrypted_extension(Engine::MZ, file_type),
+                        "fallback": fallback_extensions(file_type),
+                    })
+                })
+                .collect();
+
+            let signatures: Vec<Value> =
+                self.signatures.iter().map(Signature::to_json).collect();
+
+            println!(
+                "{}",
+                to_string_pretty(
+                    &json!({ "types": types, "custom_signatures": signatures })
+                )?
+            );
+            return Ok(());
+        }
+
+        println!(
+            "{:<6} {:<10} {:<10} {:<10} Fallback",
+            "Type", "Decrypted", "MV", "MZ"
+        );
+
+        for &file_type in FILE_TYPES {
+            let fallback = fallback_extensions(file_type);
+            let fallback = if fallback.is_empty() {
+                String::from("-")
+            } else {
+                fallback.join(", ")
+            };
+
+            println!(
+                "{:<6} {:<10} {:<10} {:<10} {fallback}",
+                file_type.to_string(),
+                decrypted_extension(file_type),
+                encrypted_extension(Engine::MV, file_type),
+                encrypted_extension(Engine::MZ, file_type),
+            );
+        }
+
+        if !self.signatures.is_empty() {
+            println!("\nCustom signatures:");
+
+            for signature in self.signatures {
+                println!("  {signature}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether the file was modified after `--since` timestamp, and counts it as skipped if not.
+    fn passes_since_filter(
+        &mut self,
+        path: &Path,
+    ) -> Result<bool, anyhow::Error> {
+        let Some(since) = self.since else {
+            return Ok(true);
+        };
+
+        // Modification time of remote files is unknown
+        if is_url(path) || metadata(path)?.modified()? >= since {
+            return Ok(true);
+        }
+
+        self.recorder.report.skipped += 1;
+        Ok(false)
+    }
+
+    /// Checks with `--skip-existing` whether the output of the file doesn't exist yet, and counts it as skipped if it does.
+    ///
+    /// Output path is computed with the new extension, so decrypting in place doesn't mistake the source for its output.
+    fn passes_existing_filter(&mut self, path: &Path, extension: &str) -> bool {
+        // Comparison writes nothing. Type of the file without known extension is only known after reading it
+        let Some(file_type) = file_type_of(extension)
+            .filter(|_| self.skip_existing && self.compare_with.is_none())
+        else {
+            return true;
+        };
+
+        let new_extension = if self.command.is_decrypt() {
+            decrypted_extension(file_type)
+        } else {
+            encrypted_extension(self.engine, file_type)
+        };
+
+        let output_file_path = self.output_file_path(path, new_extension);
+
+        if !output_file_path.exists() {
+            return true;
+        }
+
+        info!(
+            "Skipping {}: {} already exists.",
+            path.display(),
+            output_file_path.display()
+        );
+
+        self.recorder.report.skipped += 1;
+        false
+    }
+
+    /// Writes the key to `System.json`, and marks encrypted asset types as such, preserving the rest of the file and its formatting style.
+    fn write_system_json(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let content = read_to_string(path)?;
+        let mut system_value: Value = from_str(&content)?;
+
+        let Some(system) = system_value.as_object_mut() else {
+            bail!("{} is not a JSON object.", path.display());
+        };
+
+        if let Some(key) = self.decrypter.key() {
+            system.insert("encryptionKey".into(), json!(key));
+        }
+
+        let by_type = &self.recorder.report.by_type;
+
+        // Flags of asset types, that weren't encrypted in this run, may be set by previous runs
+        if by_type.contains_key(PNG_EXT) {
+            system.insert("hasEncryptedImages".into(), json!(true));
+        }
+
+        if by_type.contains_key(OGG_EXT) || by_type.contains_key(M4A_EXT) {
+            system.insert("hasEncryptedAudio".into(), json!(true));
+        }
+
+        // RPG Maker writes minified System.json, but some tools prettify it
+        let output = if content.trim_end().contains('\n') {
+            to_string_pretty(&system_value)?
+        } else {
+            to_string(&system_value)?
+        };
+
+        write_atomically(path, |file| {
+            file.write_all(output.as_bytes())?;
+            Ok(output.len())
+        })?;
+        Ok(())
+    }
+
+    /// Writes the current key and engine to the state file, so subsequent runs can skip key detection.
+    fn write_state(&self, state_file: &Path) -> Result<(), anyhow::Error> {
+        let Some(key) = self.decrypter.key() else {
+            return Ok(());
+        };
+
+        let mut state = json!({ "key": key });
+
+        if let Some(engine) = self.recorder.detected_engine {
+            state["engine"] = json!(engine.as_str());
+        }
+
+        let output = to_string_pretty(&state)?;
+
+        write_atomically(state_file, |file| {
+            file.write_all(output.as_bytes())?;
+            Ok(output.len())
+        })?;
+        Ok(())
+    }
+}
+
+/// Remembers the first detected key, and warns about files with different keys.
+fn track_detected_key(
+    first_key: &mut Option<String>,
+    key: &str,
+    source: &Path,
+) {
+    match first_key {
+        None => *first_key = Some(key.to_owned()),
+        Some(first_key) => warn!(
+            "{} has key {key}, which differs from {first_key}, detected first. Files may come from different games.",
+            source.display()
+        ),
+    }
+}
+
+/// Prints run statistics, the detected key and invalid outputs after processing.
+fn print_batch_summary(report: &RunReport, detected_key: Option<&str>) {
+    info!(
+        "Processed {} files, skipped {}, {} unique {}, {:.1} MB written",
+        report.processed,
+        report.skipped,
+        report.keys.len(),
+        if report.keys.len() == 1 {
+            "key"
+        } else {
+            "keys"
+        },
+        report.bytes_written as f64 / 1_000_000.0
+    );
+
+    if let Some(key) = detected_key {
+        info!("Detected key: {key}");
+    }
+
+    if !report.invalid.is_empty() {
+        info!("Invalid outputs: {}", report.invalid.len());
+
+        for path in &report.invalid {
+            info!("  {}", path.display());
+        }
+    }
+
+    if !report.failed.is_empty() {
+        info!("Failed files: {}", report.failed.len());
+
+        for (path, error) in &report.failed {
+            info!("  {}: {error}", path.display());
+        }
+    }
+}
+
+/// Initializes logging to stderr, with the level from `--verbose`/`--quiet`, which can be overridden by `RUST_LOG` environment variable.
+fn init_logger(cli: &Cli) {
+    let level = if cli.quiet {
+        LevelFilter::Error
+    } else {
+        match cli.verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    };
+
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            Level::Error => writeln!(buf, "ERROR: {}", record.args()),
+            Level::Warn => writeln!(buf, "WARNING: {}", record.args()),
+            _ => writeln!(buf, "{}", record.args()),
+        })
+        .init();
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    init_logger(&cli);
+    let mut processor = Processor::new(&cli)?;
+
+    let mut first_key = None;
+    processor.on_event(move |event| match event {
+        Event::KeyDetected { key, source } => {
+            track_detected_key(&mut first_key, key, source);
+        }
+        Event::BatchDone(report) => {
+            print_batch_summary(report, first_key.as_deref());
+        }
+        _ => {}
+    });
+
+    processor.process()?;
+
+    if let Some(path) = &cli.report_csv {
+        write(path, processor.recorder.report.to_csv())?;
+    }
+
+    if let Some(path) = &cli.summary_json {
+        let summary = to_string_pretty(&processor.recorder.report.to_json())?;
+
+        if path == Path::new("-") {
+            println!("{summary}");
+        } else {
+            write(path, summary)?;
+        }
+    }
+
+    if !cli.print_key_only
+        && !cli.format.is_json()
+        && !cli.command.is_types()
+        && cli.summary_json.as_deref() != Some(Path::new("-"))
+    {
+        info!(
+            "Elapsed: {:.2}s ({:.1} MB/s)",
+            processor.recorder.report.elapsed.as_secs_f32(),
+            processor.recorder.report.throughput() / 1_000_000.0
+        );
+    }
+
+    if !processor.recorder.report.failed.is_empty() {
+        bail!(
+            "{} files failed to process.",
+            processor.recorder.report.failed.len()
+        );
+    }
+
+    if cli.command.is_verify() && !processor.recorder.report.invalid.is_empty()
+    {
+        bail!(
+            "{} files failed verification.",
+            processor.recorder.report.invalid.len()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_dir_all;
+
+    fn parse_cli(args: &[&str]) -> Cli {
+        Cli::parse_from(["rpgmasd"].iter().chain(args))
+    }
+
+    /// Returns RPG Maker encrypted file with `payload` after the header.
+    fn encrypted_file(payload: &[u8]) -> Vec<u8> {
+        [RPGM_HEADER, payload].concat()
+    }
+
+    #[test]
+    fn header_only_file_has_no_payload() {
+        let cli = parse_cli(&["decrypt"]);
+        let processor = Processor::new(&cli).unwrap();
+        let mut data = encrypted_file(&[0; HEADER_LENGTH]);
+
+        let result = processor.decrypt_data(
+            &mut Decrypter::new(),
+            Path::new("header.rpgmvp"),
+            &mut data,
+            FileType::PNG,
+            None,
+        );
+
+        assert!(matches!(
+            result,
+            Err(err) if err.to_string().contains("no payload after header")
+        ));
+    }
+
+    #[test]
+    fn empty_file_is_skipped() {
+        let cli = parse_cli(&["decrypt"]);
+        let processor = Processor::new(&cli).unwrap();
+
+        let outcome = processor
+            .process_data(
+                &mut Decrypter::new(),
+                Path::new("empty.rpgmvp"),
+                MV_PNG_EXT,
+                Vec::new(),
+                None,
+            )
+            .unwrap();
+
+        assert!(outcome.status.is_skipped());
+    }
+
+    #[test]
+    fn empty_file_fails_with_strict() {
+        let cli = parse_cli(&["decrypt", "--strict"]);
+        let processor = Processor::new(&cli).unwrap();
+
+        let result = processor.process_data(
+            &mut Decrypter::new(),
+            Path::new("empty.rpgmvp"),
+            MV_PNG_EXT,
+            Vec::new(),
+            None,
+        );
+
+        assert!(matches!(
+            result,
+            Err(err) if err.to_string().contains("is empty")
+        ));
+    }
+
+    #[test]
+    fn set_hex_key_rejects_odd_length() {
+        let mut decrypter = Decrypter::new();
+
+        assert!(
+            set_hex_key(&mut decrypter, "d41d8cd98f00b204e9800998ecf8427")
+                .is_err()
+        );
+        assert!(decrypter.key().is_none());
+    }
+
+    #[test]
+    fn set_hex_key_rejects_non_hex_character() {
+        let mut decrypter = Decrypter::new();
+
+        let result =
+            set_hex_key(&mut decrypter, "d41d8cd98f00b204e9800998ecf8427g");
+
+        assert!(matches!(
+            result,
+            Err(err) if err.to_string().contains("contains `g`")
+        ));
+        assert!(decrypter.key().is_none());
+    }
+
+    #[test]
+    fn mislabeled_audio_is_decrypted_with_system_json_key() {
+        const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+        let cli = parse_cli(&["decrypt"]);
+        let mut processor = Processor::new(&cli).unwrap();
+        processor.system_json_key = Some(KEY.to_owned());
+
+        let mut decrypter = Decrypter::new();
+        decrypter.set_key_from_str(KEY).unwrap();
+        let mp3 = [b"ID3\x04\0\0\0\0\0\0".as_slice(), &[0; 64]].concat();
+        let mut data = decrypter.encrypt(&mp3).unwrap();
+
+        let result = processor
+            .decrypt_data(
+                &mut Decrypter::new(),
+                Path::new("audio.rpgmvm"),
+                &mut data,
+                FileType::M4A,
+                None,
+            )
+            .unwrap();
+
+        assert!(matches!(
+            result,
+            ControlFlow::Continue(("mp3", status)) if status.is_written()
+        ));
+        assert_eq!(&data[HEADER_LENGTH..], mp3);
+    }
+
+    /// Returns WebP image, encrypted with `key` under PNG extension, and empty temporary output directory for it.
+    fn encrypted_webp(
+        key: &str,
+        test_name: &str,
+    ) -> (Vec<u8>, Vec<u8>, PathBuf) {
+        let mut decrypter = Decrypter::new();
+        decrypter.set_key_from_str(key).unwrap();
+        let webp = [b"RIFF\x24\0\0\0WEBPVP8 ".as_slice(), &[0; 64]].concat();
+        let data = decrypter.encrypt(&webp).unwrap();
+
+        let output_dir = std::env::temp_dir()
+            .join(format!("rpgmasd-{test_name}-{}", std::process::id()));
+        let _ = remove_dir_all(&output_dir);
+
+        (webp, data, output_dir)
+    }
+
+    #[test]
+    fn webp_is_decrypted_with_key() {
+        const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+        let (webp, data, output_dir) = encrypted_webp(KEY, "webp-key");
+        let cli = parse_cli(&[
+            "decrypt",
+            "--key",
+            KEY,
+            "-o",
+            output_dir.to_str().unwrap(),
+        ]);
+        let processor = Processor::new(&cli).unwrap();
+
+        let mut decrypter = Decrypter::new();
+        decrypter.set_key_from_str(KEY).unwrap();
+
+        let outcome = processor
+            .process_data(
+                &mut decrypter,
+                Path::new("image.rpgmvp"),
+                MV_PNG_EXT,
+                data,
+                None,
+            )
+            .unwrap();
+
+        assert!(outcome.status.is_written());
+        assert_eq!(outcome.output, output_dir.join("image.webp"));
+        assert_eq!(read(&outcome.output).unwrap(), webp);
+
+        remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[test]
+    fn webp_is_decrypted_with_system_json_key() {
+        const KEY: &str = "0123456789abcdef0123456789abcdef";
+
+        let (webp, data, output_dir) = encrypted_webp(KEY, "webp-system-json");
+        let cli = parse_cli(&["decrypt", "-o", output_dir.to_str().unwrap()]);
+        let mut processor = Processor::new(&cli).unwrap();
+        processor.system_json_key = Some(KEY.to_owned());
+
+        let outcome = processor
+            .process_data(
+                &mut Decrypter::new(),
+                Path::new("image.rpgmvp"),
+                MV_PNG_EXT,
+                data,
+                None,
+            )
+            .unwrap();
+
+        assert!(outcome.status.is_written());
+        assert_eq!(outcome.key.as_deref(), Some(KEY));
+        assert_eq!(outcome.output, output_dir.join("image.webp"));
+        assert_eq!(read(&outcome.output).unwrap(), webp);
+
+        remove_dir_all(&output_dir).unwrap();
+    }
+}
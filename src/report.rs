@@ -0,0 +1,200 @@
+use crate::Engine;
+use asset_decrypter::FileType;
+use serde_json::{Value, json};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use strum_macros::EnumIs;
+
+#[derive(Clone, Copy, EnumIs)]
+pub enum Status {
+    /// Output was successfully written.
+    Written,
+    /// Decrypted file had invalid signature, and was written with `.invalid` suffix.
+    Invalid,
+    /// Decrypted file was compared against the reference, nothing was written.
+    Compared,
+    /// Decrypted file had valid signature in `verify` command, nothing was written.
+    Verified,
+    /// File was skipped without writing anything.
+    Skipped,
+    /// Output path was computed in `--dry-run` mode, nothing was read or written.
+    Planned,
+}
+
+impl Status {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Written => "written",
+            Self::Invalid => "invalid",
+            Self::Compared => "compared",
+            Self::Verified => "verified",
+            Self::Skipped => "skipped",
+            Self::Planned => "planned",
+        }
+    }
+}
+
+/// Result of processing a single file.
+pub struct ProcessOutcome {
+    pub source: PathBuf,
+    pub output: PathBuf,
+    pub file_type: FileType,
+    /// Engine, which extension the source file has.
+    pub engine: Option<Engine>,
+    pub key: Option<String>,
+    pub bytes: u64,
+    pub status: Status,
+}
+
+impl ProcessOutcome {
+    /// Creates outcome of the file, that was processed without writing anything.
+    pub fn unwritten(
+        source: &Path,
+        output: &Path,
+        file_type: FileType,
+        engine: Option<Engine>,
+        key: Option<&str>,
+        status: Status,
+    ) -> Self {
+        Self {
+            source: source.to_path_buf(),
+            output: output.to_path_buf(),
+            file_type,
+            engine,
+            key: key.map(str::to_owned),
+            bytes: 0,
+            status,
+        }
+    }
+}
+
+/// Outcome of a processed file, along with the time it took to process it.
+pub struct FileRecord {
+    pub outcome: ProcessOutcome,
+    pub elapsed: Duration,
+}
+
+/// Aggregated results of a single run.
+#[derive(Default)]
+pub struct RunReport {
+    pub files: Vec<FileRecord>,
+    pub processed: usize,
+    pub by_type: BTreeMap<String, usize>,
+    pub bytes_written: u64,
+    pub invalid: Vec<PathBuf>,
+    pub skipped: usize,
+    /// Files, that failed to process, along with error messages.
+    pub failed: Vec<(PathBuf, String)>,
+    pub keys: BTreeSet<String>,
+    pub elapsed: Duration,
+}
+
+impl RunReport {
+    /// Aggregates the outcome of a processed file.
+    pub fn add(&mut self, outcome: ProcessOutcome, elapsed: Duration) {
+        match outcome.status {
+            Status::Written | Status::Verified | Status::Planned => {
+                self.processed += 1;
+                self.bytes_written += outcome.bytes;
+                *self
+                    .by_type
+                    .entry(outcome.file_type.to_string())
+                    .or_default() += 1;
+            }
+            Status::Invalid => self.invalid.push(outcome.output.clone()),
+            Status::Skipped => self.skipped += 1,
+            Status::Compared => {}
+        }
+
+        if let Some(key) = &outcome.key {
+            self.keys.insert(key.clone());
+        }
+
+        self.files.push(FileRecord { outcome, elapsed });
+    }
+
+    /// Returns written bytes per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+
+        if secs == 0.0 {
+            0.0
+        } else {
+            self.bytes_written as f64 / secs
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "processed": self.processed,
+            "by_type": self.by_type,
+            "bytes_written": self.bytes_written,
+            "invalid": self.invalid.len(),
+            "skipped": self.skipped,
+            "failed": self.failed.iter().map(|(path, error)| json!({ "file": path.to_string_lossy(), "error": error })).collect::<Vec<_>>(),
+            "keys": self.keys,
+            "elapsed_secs": self.elapsed.as_secs_f64(),
+            "bytes_per_sec": self.throughput(),
+        })
+    }
+
+    /// Returns CSV table of all processed files.
+    pub fn to_csv(&self) -> String {
+        let mut csv =
+            String::from("source,output,type,key,status,bytes,ms,engine\n");
+
+        for FileRecord { outcome, elapsed } in &self.files {
+            let row = [
+                escape_csv(&outcome.source.to_string_lossy()),
+                escape_csv(&outcome.output.to_string_lossy()),
+                outcome.file_type.to_string(),
+                outcome.key.clone().unwrap_or_default(),
+                outcome.status.as_str().to_owned(),
+                outcome.bytes.to_string(),
+                elapsed.as_millis().to_string(),
+                outcome
+                    .engine
+                    .map(Engine::as_str)
+                    .unwrap_or_default()
+                    .to_owned(),
+            ];
+
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+
+        csv
+    }
+}
+
+/// Quotes the field if it contains characters, that have special meaning in CSV.
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn to_json_handles_non_utf8_failed_paths() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let mut report = RunReport::default();
+        report.failed.push((
+            PathBuf::from(OsStr::from_bytes(b"bad\xFF.rpgmvp")),
+            String::from("error"),
+        ));
+
+        let json = report.to_json();
+        assert_eq!(json["failed"][0]["file"], "bad\u{FFFD}.rpgmvp");
+    }
+}
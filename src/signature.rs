@@ -0,0 +1,440 @@
+use crate::{FILE_TYPES, decrypted_extension, to_hex};
+use anyhow::bail;
+use asset_decrypter::{Decrypter, FileType, HEADER_LENGTH, RPGM_HEADER};
+use serde_json::{Value, json};
+use std::{fmt, str::FromStr};
+
+const MP3_EXT: &str = "mp3";
+const AAC_EXT: &str = "aac";
+const WEBP_EXT: &str = "webp";
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const OGG_SIGNATURE: &[u8] = b"OggS";
+const M4A_SIGNATURE: &[u8] = b"ftyp";
+const RIFF_SIGNATURE: &[u8] = b"RIFF";
+const WEBP_SIGNATURE: &[u8] = b"WEBP";
+
+/// Boxes, that usually follow `ftyp` box in M4A files.
+const M4A_POST_FTYP_BOXES: &[&[u8]] =
+    &[b"moov", b"mdat", b"free", b"skip", b"wide", b"pnot"];
+
+/// Maximum length of the first page in OGG files, which only holds the codec identification header: 47 bytes with Opus, 58 with Vorbis and 79 with FLAC.
+const OGG_FIRST_PAGE_MAX_LENGTH: usize = 80;
+
+/// Offset of the chunk type, following IHDR chunk in PNG files.
+const PNG_POST_IHDR_CHUNK_OFFSET: usize = 37;
+
+/// Range of IHDR chunk type and data in PNG files, which IHDR CRC covers.
+const PNG_IHDR_RANGE: std::ops::Range<usize> = 12..29;
+
+/// Extensions, which decrypted M4A files may get if they're actually mislabeled MP3 or AAC streams.
+const M4A_FALLBACK_EXTENSIONS: &[&str] = &[MP3_EXT, AAC_EXT];
+
+/// Extensions, which decrypted PNG files may get if they're actually WebP images, which MZ projects sometimes ship under PNG extensions.
+const PNG_FALLBACK_EXTENSIONS: &[&str] = &[WEBP_EXT];
+
+/// Returns extensions, which decrypted files of `file_type` may get instead of the default one, if their content has another format.
+pub const fn fallback_extensions(
+    file_type: FileType,
+) -> &'static [&'static str] {
+    match file_type {
+        FileType::PNG => PNG_FALLBACK_EXTENSIONS,
+        FileType::OGG => &[],
+        FileType::M4A => M4A_FALLBACK_EXTENSIONS,
+    }
+}
+
+/// Validates signatures of decrypted data.
+///
+/// Implement it to accept custom content formats, that built-in validation rejects. Validators are shared between `--jobs` worker threads, so they must be [`Sync`].
+pub trait SignatureValidator: Sync {
+    /// Validates decrypted data, which is expected to be of `file_type`.
+    ///
+    /// # Returns
+    ///
+    /// - Extension of the output file, if data has valid signature.
+    /// - [`None`] otherwise.
+    fn validate(&self, data: &[u8], file_type: FileType) -> Option<&str>;
+}
+
+/// Built-in validator, which checks PNG/OGG/M4A signatures.
+///
+/// Since RPG Maker audio is sometimes mislabeled across codecs, M4A files that are actually MP3 or ADTS AAC streams are accepted as well. Likewise, PNG files that are actually WebP images are accepted, and get `.webp` extension.
+///
+/// Key detected from the file reproduces PNG/M4A signature by construction, so these fallbacks need the actual key of the game. With the detected one, mislabeled files are still rejected, since their unencrypted bytes don't match the signature.
+pub struct BuiltinValidator;
+
+impl SignatureValidator for BuiltinValidator {
+    fn validate(&self, data: &[u8], file_type: FileType) -> Option<&str> {
+        if has_signature(data, file_type)
+            && has_consistent_structure(data, file_type)
+        {
+            Some(decrypted_extension(file_type))
+        } else if file_type.is_m4a() {
+            detect_mislabeled_audio(data)
+        } else if file_type.is_png() && is_webp(data) {
+            Some(WEBP_EXT)
+        } else {
+            None
+        }
+    }
+}
+
+/// Validator, which checks built-in signatures first, and user-registered ones after.
+pub struct CustomValidator {
+    signatures: Vec<Signature>,
+}
+
+impl CustomValidator {
+    pub fn new(signatures: Vec<Signature>) -> Self {
+        Self { signatures }
+    }
+}
+
+impl SignatureValidator for CustomValidator {
+    fn validate(&self, data: &[u8], file_type: FileType) -> Option<&str> {
+        BuiltinValidator.validate(data, file_type).or_else(|| {
+            self.signatures
+                .iter()
+                .find(|signature| signature.matches(data))
+                .map(|signature| signature.extension.as_str())
+        })
+    }
+}
+
+/// Custom content signature, which decrypt validation accepts in addition to built-in PNG/OGG/M4A signatures.
+#[derive(Clone)]
+pub struct Signature {
+    magic: Vec<u8>,
+    offset: usize,
+    extension: String,
+}
+
+impl Signature {
+    fn matches(&self, data: &[u8]) -> bool {
+        self.offset
+            .checked_add(self.magic.len())
+            .and_then(|end| data.get(self.offset..end))
+            == Some(self.magic.as_slice())
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "magic": to_hex(&self.magic),
+            "offset": self.offset,
+            "extension": self.extension,
+        })
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}@{} => {}",
+            to_hex(&self.magic),
+            self.offset,
+            self.extension
+        )
+    }
+}
+
+impl FromStr for Signature {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (magic_offset, extension) = value
+            .split_once('=')
+            .ok_or("expected `magic@offset=ext` format")?;
+        let (magic, offset) = magic_offset
+            .split_once('@')
+            .ok_or("expected `magic@offset=ext` format")?;
+
+        // Slicing non-ASCII strings into pairs could split a character
+        if magic.is_empty()
+            || magic.len() % 2 != 0
+            || !magic.bytes().all(|byte| byte.is_ascii_hexdigit())
+        {
+            return Err("magic must be a non-empty hex string".into());
+        }
+
+        let magic = (0..magic.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&magic[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| "magic must be a non-empty hex string")?;
+
+        let offset = offset
+            .parse()
+            .map_err(|_| "offset must be a non-negative integer")?;
+
+        if extension.is_empty() {
+            return Err("extension must not be empty".into());
+        }
+
+        // Extension replaces the one of output path, so it must not point elsewhere
+        if extension.contains(['/', '\\', '.']) {
+            return Err(
+                "extension must not contain path separators or dots".into()
+            );
+        }
+
+        Ok(Self {
+            magic,
+            offset,
+            extension: extension.to_owned(),
+        })
+    }
+}
+
+fn has_signature(data: &[u8], file_type: FileType) -> bool {
+    match file_type {
+        FileType::PNG => data.starts_with(PNG_SIGNATURE),
+        FileType::OGG => data.starts_with(OGG_SIGNATURE),
+        // First four bytes are box size, which differs between files
+        FileType::M4A => data.len() >= 12 && &data[4..8] == M4A_SIGNATURE,
+    }
+}
+
+/// Checks that unencrypted data, following the encrypted header, agrees with the signature: PNG IHDR CRC matches IHDR chunk, and M4A `ftyp` box is followed by another box.
+fn has_consistent_structure(data: &[u8], file_type: FileType) -> bool {
+    match file_type {
+        FileType::PNG => data
+            .get(PNG_IHDR_RANGE)
+            .zip(data.get(PNG_IHDR_RANGE.end..PNG_IHDR_RANGE.end + 4))
+            .is_some_and(|(ihdr, crc)| crc == crc32(ihdr).to_be_bytes()),
+        FileType::OGG => true,
+        FileType::M4A => {
+            let ftyp_size =
+                u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+                    as usize;
+
+            // Box types are four printable characters
+            data.get(ftyp_size.saturating_add(4)..ftyp_size.saturating_add(8))
+                .is_some_and(|box_type| {
+                    box_type.iter().all(|&byte| {
+                        byte.is_ascii_alphanumeric() || byte == b' '
+                    })
+                })
+        }
+    }
+}
+
+/// Computes CRC-32, which PNG uses for its chunks.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+
+    for &byte in data {
+        crc ^= u32::from(byte);
+
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+
+    !crc
+}
+
+/// Detects type of the encrypted file by its content, regardless of its extension.
+///
+/// If `decrypter` has a key, encrypted header is decrypted and checked against built-in signatures. Otherwise, or if the key doesn't fit, unencrypted rest of the file is checked for traces of the format: M4A boxes, capture pattern of the second OGG page or PNG chunk type.
+///
+/// # Returns
+///
+/// - Detected [`FileType`].
+/// - [`None`] if data isn't RPG Maker encrypted file, or its type can't be detected.
+pub fn detect_file_type(
+    data: &[u8],
+    decrypter: &mut Decrypter,
+) -> Option<FileType> {
+    let header_end = RPGM_HEADER.len() + HEADER_LENGTH;
+
+    if !data.starts_with(RPGM_HEADER) || data.len() <= header_end {
+        return None;
+    }
+
+    if decrypter.key().is_some() {
+        let mut header = data[..header_end].to_vec();
+
+        // File type is only used to detect the key, which is already set
+        if let Ok(decrypted) =
+            decrypter.decrypt_in_place(&mut header, FileType::PNG)
+            && let Some(&file_type) = FILE_TYPES
+                .iter()
+                .find(|&&file_type| has_signature(decrypted, file_type))
+        {
+            return Some(file_type);
+        }
+    }
+
+    let plain = &data[header_end..];
+
+    if plain
+        .windows(4)
+        .take(64)
+        .any(|w| M4A_POST_FTYP_BOXES.contains(&w))
+    {
+        Some(FileType::M4A)
+    } else if plain
+        .windows(4)
+        .take(OGG_FIRST_PAGE_MAX_LENGTH - HEADER_LENGTH)
+        .any(|w| w == OGG_SIGNATURE)
+    {
+        Some(FileType::OGG)
+    } else if data
+        .get(RPGM_HEADER.len() + PNG_POST_IHDR_CHUNK_OFFSET..)
+        .and_then(|chunk| chunk.get(..4))
+        .is_some_and(|chunk_type| {
+            chunk_type.iter().all(u8::is_ascii_alphabetic)
+        })
+    {
+        Some(FileType::PNG)
+    } else {
+        None
+    }
+}
+
+/// Returns whether the data is a WebP image: RIFF container with `WEBP` form type, following the chunk size.
+fn is_webp(data: &[u8]) -> bool {
+    data.starts_with(RIFF_SIGNATURE) && data.get(8..12) == Some(WEBP_SIGNATURE)
+}
+
+/// Detects MP3 and ADTS AAC streams, which are sometimes mislabeled as M4A.
+///
+/// # Returns
+///
+/// - Extension of the detected format.
+/// - [`None`] if data is neither MP3 nor AAC.
+fn detect_mislabeled_audio(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"ID3") {
+        return Some(MP3_EXT);
+    }
+
+    match data {
+        // ADTS: 12-bit sync word, followed by MPEG version bit and always zero layer bits
+        [0xFF, second, ..] if second & 0xF6 == 0xF0 => Some(AAC_EXT),
+        // MPEG audio frame: 11-bit sync word, followed by version and non-reserved layer bits
+        [0xFF, second, ..] if second & 0xE0 == 0xE0 && second & 0x06 != 0 => {
+            Some(MP3_EXT)
+        }
+        _ => None,
+    }
+}
+
+/// Checks that decrypted data starts with a valid signature of the passed file type.
+///
+/// # Returns
+///
+/// - Extension of the output file, if signature is valid.
+/// - Error otherwise.
+pub fn verify_decrypted<'v>(
+    data: &[u8],
+    file_type: FileType,
+    validator: &'v dyn SignatureValidator,
+) -> Result<&'v str, anyhow::Error> {
+    let Some(extension) = validator.validate(data, file_type) else {
+        bail!(
+            "Decrypted {} file has invalid signature. Check if you supplied correct key in `--key` argument.",
+            file_type.to_string().to_uppercase()
+        );
+    };
+
+    Ok(extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns PNG signature, followed by 1x1 IHDR chunk with valid CRC.
+    fn png_header() -> Vec<u8> {
+        let mut data = PNG_SIGNATURE.to_vec();
+        data.extend(b"\0\0\0\x0dIHDR\0\0\0\x01\0\0\0\x01\x08\x06\0\0\0");
+        let crc = crc32(&data[PNG_IHDR_RANGE]);
+        data.extend(crc.to_be_bytes());
+        data
+    }
+
+    /// Returns M4A `ftyp` box, followed by `moov` box header.
+    fn m4a_header() -> Vec<u8> {
+        let mut data = b"\0\0\0\x1cftypM4A \0\0\x02\0".to_vec();
+        data.extend(b"M4A mp42isom\0\0\0\x08moov");
+        data
+    }
+
+    #[test]
+    fn verify_decrypted_accepts_valid_payloads() {
+        for (data, file_type, extension) in [
+            (png_header(), FileType::PNG, "png"),
+            (b"RIFF\x24\0\0\0WEBPVP8 ".to_vec(), FileType::PNG, "webp"),
+            (b"OggS\0\x02".to_vec(), FileType::OGG, "ogg"),
+            (m4a_header(), FileType::M4A, "m4a"),
+        ] {
+            assert_eq!(
+                verify_decrypted(&data, file_type, &BuiltinValidator).unwrap(),
+                extension
+            );
+        }
+    }
+
+    #[test]
+    fn verify_decrypted_rejects_corrupted_payloads() {
+        let corrupted = [0xAB; 16];
+
+        for file_type in FILE_TYPES {
+            assert!(
+                verify_decrypted(&corrupted, *file_type, &BuiltinValidator)
+                    .is_err()
+            );
+        }
+
+        // Valid signature of another type
+        assert!(
+            verify_decrypted(&png_header(), FileType::OGG, &BuiltinValidator)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn signature_rejects_unsafe_values() {
+        for value in ["7e@0=../../evil", "7e@0=a/b", "7e@0=a\\b", "7e@0=tar.gz"]
+        {
+            assert!(value.parse::<Signature>().is_err());
+        }
+
+        let signature: Signature = "00@18446744073709551615=x".parse().unwrap();
+        assert!(!signature.matches(&[0; 4]));
+    }
+
+    /// Detected keys reproduce the signature in the encrypted header, while the rest of the file has another format.
+    #[test]
+    fn verify_decrypted_rejects_signature_without_matching_structure() {
+        let mut png = png_header();
+        png[16] ^= 0xFF;
+        assert!(
+            verify_decrypted(&png, FileType::PNG, &BuiltinValidator).is_err()
+        );
+
+        let mut m4a = m4a_header();
+        m4a[32..36].fill(0xFF);
+        assert!(
+            verify_decrypted(&m4a, FileType::M4A, &BuiltinValidator).is_err()
+        );
+    }
+
+    #[test]
+    fn detect_file_type_only_finds_ogg_page_after_first_one() {
+        let ogg = |page_offset: usize| {
+            let mut data = [RPGM_HEADER, &[0; 512]].concat();
+            let offset = RPGM_HEADER.len() + page_offset;
+            data[offset..offset + 4].copy_from_slice(OGG_SIGNATURE);
+            data
+        };
+
+        assert!(matches!(
+            detect_file_type(&ogg(58), &mut Decrypter::new()),
+            Some(FileType::OGG)
+        ));
+        assert!(detect_file_type(&ogg(400), &mut Decrypter::new()).is_none());
+    }
+}